@@ -0,0 +1,52 @@
+use std::hint::black_box;
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use formatter::{format, Config};
+
+/// Generates prose with list items and URLs of the specified number of lines (deterministically)
+fn generate_input(lines: usize) -> String {
+    const WORDS: [&str; 14] = ["lorem", "ipsum", "dolor", "sit", "amet,", "consectetur", "adipiscing", "elit.", "sed", "do", "- item", "1. first", "https://example.com/path", "tempor"];
+    let mut seed = 1u32;
+    let mut next = |max: u32| {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        (seed >> 16) % max
+    };
+    let mut input = String::new();
+    for _ in 0..lines {
+        let words = 3 + next(28);
+        for index in 0..words {
+            if index > 0 {
+                input.push(' ');
+            }
+            input.push_str(WORDS[next(WORDS.len() as u32) as usize]);
+        }
+        input.push('\n');
+    }
+    input
+}
+
+fn bench_format(c: &mut Criterion) {
+    let input = generate_input(100_000);
+    let configs = [
+        ("unlimited", Config::default()),
+        ("wrap", Config { max_line_length: 40, ..Config::default() }),
+        ("rewrap", Config { max_line_length: 40, rewrap: true, preserve_list_indentation: true, ..Config::default() }),
+    ];
+    let mut group = c.benchmark_group("format");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.sample_size(10);
+    for (name, config) in configs {
+        group.bench_function(name, |b| {
+            let mut output = Vec::with_capacity(input.len() * 2);
+            b.iter(|| {
+                output.clear();
+                format(&config, &mut Cursor::new(black_box(&input)), &mut output).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_format);
+criterion_main!(benches);
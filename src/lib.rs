@@ -1,337 +1,4068 @@
-use std::io::{BufRead, BufReader, Write};
-use std::fs::File;
-use clap::Parser;
-use itertools::{Itertools,EitherOrBoth::*};
-use regex::Regex;
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Write};
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::{self, File};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use itertools::Itertools;
+use regex::{Regex, RegexBuilder};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum WidthMode {
+    /// Count each Unicode scalar value as one column
+    #[default]
+    Chars,
+    /// Count the number of columns each character occupies in a terminal (e.g. two for CJK characters)
+    Display,
+    /// Like display but measure grapheme clusters (e.g. emoji sequences) as a whole and never break within them
+    Grapheme,
+    /// Count the number of bytes each character occupies in UTF-8 (e.g. for protocols limiting the line length
+    /// in bytes); lines are still only broken between characters
+    Bytes,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum AnsiMode {
+    /// Treat ANSI escape sequences like any other characters
+    #[default]
+    Keep,
+    /// Keep ANSI escape sequences but do not count them when measuring the line length
+    Ignore,
+    /// Remove ANSI escape sequences
+    Strip,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Encoding {
+    /// Fail on input that is not valid UTF-8
+    #[default]
+    Strict,
+    /// Replace invalid UTF-8 sequences with U+FFFD
+    Lossy,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LineEnding {
+    /// Terminate lines with "\n"
+    #[default]
+    Lf,
+    /// Terminate lines with "\r\n"
+    Crlf,
+    /// Terminate lines like the corresponding input line is terminated
+    Preserve,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Write the formatted lines as plain text
+    #[default]
+    Text,
+    /// Write a JSON array with an object for each formatted line containing the line (without terminator),
+    /// the number of the input line it originates from and whether it is the result of wrapping
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Alignment {
+    /// Leave lines as they are
+    #[default]
+    Left,
+    /// Center lines within the maximum line length
+    Center,
+    /// Align lines to the right within the maximum line length
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Case {
+    /// Convert all characters to uppercase
+    Upper,
+    /// Convert all characters to lowercase
+    Lower,
+    /// Convert the first character of each word to uppercase and the remaining characters to lowercase
+    Title,
+}
+
+/// A range of input line numbers (1-based and inclusive) as specified via --lines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl LineRange {
+    pub fn contains(&self, line_number: usize) -> bool {
+        (self.start..=self.end).contains(&line_number)
+    }
+}
+
+fn parse_line_range(s: &str) -> Result<LineRange, String> {
+    let (start, end) = s.split_once(':').ok_or("expected START:END")?;
+    let start = start.parse::<usize>().map_err(|error| format!("invalid start: {}", error))?;
+    let end = end.parse::<usize>().map_err(|error| format!("invalid end: {}", error))?;
+    if start == 0 || start > end {
+        return Err("expected 1 <= START <= END".to_owned());
+    }
+    Ok(LineRange { start, end })
+}
+
+fn parse_byte_range(s: &str) -> Result<std::ops::Range<usize>, String> {
+    let (start, end) = s.split_once(':').ok_or("expected START:END")?;
+    let start = start.parse::<usize>().map_err(|error| format!("invalid start: {}", error))?;
+    let end = end.parse::<usize>().map_err(|error| format!("invalid end: {}", error))?;
+    if start >= end {
+        return Err("expected START < END".to_owned());
+    }
+    Ok(start..end)
+}
 
 #[derive(Parser)]
-#[command(author, version, about = "Formats the given input according to specified options", long_about = None)]
+#[command(author, version, about = "Formats the given input according to specified options", long_about = None,
+    after_help = "Each option can also be specified via an environment variable, e.g. FORMATTER_MAX_LINE_LENGTH=80. Options specified on the command-line take precedence over environment variables which take precedence over the configuration file (see --config).")]
 struct Cli {
+    #[command(flatten)]
+    config: Config,
+    #[arg(short, long, conflicts_with = "in_place", help = "Write the output to the specified file instead of stdout")]
+    output: Option<String>,
+    #[arg(long, help = "Substitutes the specified string (instead of a regex) with the --replacement at the same position; rules are applied in the order they are specified together with --substitute-regex")]
+    substitute_literal: Vec<String>,
+    #[arg(long, default_value_t = false, help = "Use the width of the terminal as --max-line-length (or 80 if the output is no terminal); an explicitly specified --max-line-length takes precedence")]
+    auto_width: bool,
+    #[arg(long = "config", id = "config_file", help = "Read default values for the options from the specified TOML file instead of .formatterrc in the current directory; keys are the option names with underscores (e.g. max_line_length = 80) and options specified on the command-line take precedence")]
+    config_file: Option<String>,
+}
+
+/// The options controlling how the input is formatted
+///
+/// The defaults correspond to the defaults of the command-line interface.
+#[derive(Args, Clone, Debug)]
+pub struct Config {
     #[arg(short, long, default_value_t = 0, help = "Break lines that exceed the specified number of characters; specify 0 for no line limit")]
-    max_line_length: usize,
-    #[arg(short, long, default_value_t = false, help = "Break words when breaking lines via --max-line-length")]
-    break_words: bool,
-    #[arg(short, long, default_value_t = false, help = "Keep trailing whitespaces")]
-    keep_trailing_whitespaces: bool,
-    #[arg(short, long, default_value_t = false, help = "Preserve list indentation when breaking lines via --max-line-length (does not fix existing list indentation)")]
-    preserve_list_indentation: bool,
+    pub max_line_length: usize,
+    #[arg(long, default_value_t = 0, help = "Allow lines to exceed --max-line-length by up to the specified number of columns before breaking them (alignment and justification still use --max-line-length)")]
+    pub wrap_tolerance: usize,
+    #[arg(long, default_value_t = 0, conflicts_with = "truncate", help = "Break lines that would otherwise contain more than the specified number of words (when combined with --max-line-length lines are broken at whichever limit is hit first); specify 0 for no word limit")]
+    pub max_words: usize,
+    #[arg(long, help = "Break the first line of each input line (or of each paragraph when rewrapping) at the specified width instead of --max-line-length, e.g. to leave room for a preceding label")]
+    pub first_line_width: Option<usize>,
+    #[arg(long, default_value_t = false, conflicts_with = "rewrap", help = "Cut lines exceeding --max-line-length and discard the rest instead of breaking them")]
+    pub truncate: bool,
+    #[arg(long, requires = "truncate", help = "Append the specified string (e.g. \"…\") to lines cut via --truncate; its width is included in --max-line-length")]
+    pub ellipsis: Option<String>,
+    #[arg(long, value_enum, default_value_t = WidthMode::Chars, help = "How to measure the line length for --max-line-length")]
+    pub width_mode: WidthMode,
+    #[arg(long, value_enum, default_value_t = AnsiMode::Keep, help = "How to deal with ANSI escape sequences like color codes")]
+    pub ansi: AnsiMode,
+    #[arg(short, long, default_value_t = false, help = "Break words when breaking lines via --max-line-length (except URLs starting with http:// or https://)")]
+    pub break_words: bool,
+    #[arg(long, default_value_t = false, help = "Allow breaking lines after hyphens and slashes within words (keeping the hyphen/slash on the first line)")]
+    pub break_on_hyphens: bool,
+    #[arg(long, default_value = "", help = "Further characters within words after which lines may be broken (keeping the character on the first line), e.g. \"/,\"")]
+    pub extra_break_chars: String,
+    #[arg(long, default_value = "", help = "Characters after which lines are preferably broken via --max-line-length (keeping the character on the first line), e.g. \",;\"")]
+    pub wrap_after: String,
+    #[arg(long, default_value = "\u{a0}\u{2007}\u{202f}", help = "Whitespace characters which must not be used to break lines (by default no-break, figure and narrow no-break space)")]
+    pub no_break_chars: String,
+    #[arg(short, long, default_value_t = false, overrides_with = "trim_trailing", help = "Keep trailing whitespaces")]
+    pub keep_trailing_whitespaces: bool,
+    #[arg(long, default_value_t = false, overrides_with = "keep_trailing_whitespaces", help = "Trim trailing whitespaces of output lines (the default, overrides --keep-trailing-whitespaces)")]
+    pub trim_trailing: bool,
+    #[arg(short, long, default_value_t = false, help = "Preserve list indentation when breaking lines via --max-line-length (does not fix existing list indentation, see --normalize-list-indentation)")]
+    pub preserve_list_indentation: bool,
+    #[arg(long, default_value = "*-", help = "Characters that start a list item when used as the first non-whitespace character of a line")]
+    pub list_markers: String,
+    #[arg(long, default_value_t = false, help = "Re-indent lines following a list item to the indentation of the item's text (implies --preserve-list-indentation)")]
+    pub normalize_list_indentation: bool,
+    #[arg(long, default_value_t = false, help = "Repeat blockquote prefixes like \"> \" when breaking lines and treat a change of the quote depth as new paragraph when rewrapping")]
+    pub preserve_blockquotes: bool,
+    #[arg(long, default_value_t = false, help = "Never break lines within Markdown code spans (like \"`some code`\") and link targets (like \"](https://example.com)\")")]
+    pub markdown: bool,
+    #[arg(long, help = "Repeat the specified comment prefix (e.g. \"//\" or \"#\") when breaking lines starting with it and treat a change between commented and uncommented lines as new paragraph when rewrapping")]
+    pub comment_prefix: Option<String>,
+    #[arg(long, default_value_t = 0, help = "Indent lines resulting from breaking a line by the specified number of spaces; the indentation of list items takes precedence")]
+    pub hanging_indent: usize,
+    #[arg(long, value_parser = Regex::new, help = "Align continuation lines of lines starting with a match of the specified regex (e.g. \"^\\w+:\\s+\" for labels like \"Note: \") under the text following the match when breaking lines")]
+    pub label_indent: Option<Regex>,
+    #[arg(long, value_parser = Regex::new, help = "Only break (and when rewrapping join) lines matching the specified regex; other lines are passed through as-is and end the paragraph being rewrapped")]
+    pub wrap_if: Option<Regex>,
+    #[arg(long, default_value_t = false, help = "Pass lines looking like table rows through as-is like lines not matching --wrap-if: lines starting and ending with \"|\" (or with \"+\" for borders like \"+---+\") and lines with at least three columns separated by runs of two or more spaces")]
+    pub detect_tables: bool,
+    #[arg(long, value_parser = Regex::new, help = "Never break (and when rewrapping never join) lines matching the specified regex; substitutions are still applied to them")]
+    pub no_wrap_if: Option<Regex>,
+    #[arg(long, value_parser = Regex::new, help = "Remove a match of the specified regex at the beginning of each line (e.g. a timestamp or log level) before any other processing")]
+    pub strip_prefix: Option<Regex>,
+    #[arg(long, help = "Pass lines between lines starting with the specified delimiter (e.g. \"```\") through verbatim, without substitutions, breaking, alignment and trimming trailing whitespaces; decorations like --indent and --prefix are still added")]
+    pub verbatim_delimiter: Option<String>,
+    #[arg(long, value_name = "START:END", value_parser = parse_line_range, help = "Only format the input lines within the specified range (1-based and inclusive, e.g. \"2:3\"); other lines are passed through unchanged, without decorations like --indent, --prefix and --number-lines")]
+    pub lines: Option<LineRange>,
+    #[arg(long, value_name = "START:END", value_parser = parse_byte_range, help = "Only format the input lines overlapping the specified range of byte offsets (0-based, END exclusive, e.g. \"0:42\"); a line is formatted as a whole even if the range starts or ends in the middle of it (also in the middle of a word); other lines are passed through unchanged, without decorations like --indent, --prefix and --number-lines")]
+    pub byte_range: Option<std::ops::Range<usize>>,
+    #[arg(long, default_value_t = 0, help = "Indent all output lines by the specified number of spaces; the indentation counts towards --max-line-length")]
+    pub indent: usize,
+    #[arg(long, conflicts_with = "indent", help = "Indent all output lines with the specified string; the indentation counts towards --max-line-length")]
+    pub indent_string: Option<String>,
+    #[arg(long, help = "Prepend the specified string to each output line; it counts towards --max-line-length")]
+    pub prefix: Option<String>,
+    #[arg(long, help = "Append the specified string to each output line, padding lines to --max-line-length so suffixes are aligned; it counts towards --max-line-length")]
+    pub suffix: Option<String>,
+    #[arg(long, default_value_t = 0, help = "Keep the specified number of columns at the end of each line empty by wrapping earlier; must be less than --max-line-length")]
+    pub right_margin: usize,
     #[arg(short, long, default_value_t = false, help = "Join lines that would otherwise be shorter than the maximum specified via --max-line-length")]
-    rewrap: bool,
+    pub rewrap: bool,
+    #[arg(long, default_value_t = false, requires = "rewrap", help = "Treat lines whose indentation differs from the indentation of the paragraph (or of the list item's text) as new paragraph when rewrapping")]
+    pub rewrap_respect_indent: bool,
+    #[arg(long, default_value_t = false, requires = "rewrap", help = "Do not join a line ending a sentence (with \".\", \"!\" or \"?\" optionally followed by a quote or closing parenthesis) with the next line when rewrapping")]
+    pub rewrap_keep_sentences: bool,
+    #[arg(long, default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), requires = "rewrap", help = "The number of spaces to insert after a sentence-ending line (ending with \".\", \"!\" or \"?\" optionally followed by a quote or closing parenthesis) when joining lines via --rewrap")]
+    pub sentence_spacing: usize,
+    #[arg(long, default_value_t = 0, requires = "rewrap", help = "Only join lines when rewrapping if the line to append to is shorter than the specified length (also with an unlimited --max-line-length); specify 0 to always join")]
+    pub rewrap_min_length: usize,
+    #[arg(long, default_value_t = false, help = "Treat lines indented by at least --indented-code-width columns as code (like Markdown does): they are never joined when rewrapping and their indentation is repeated when breaking them")]
+    pub preserve_indented_code: bool,
+    #[arg(long, default_value_t = 4, requires = "preserve_indented_code", help = "The indentation from which on lines are treated as code via --preserve-indented-code")]
+    pub indented_code_width: usize,
+    #[arg(long, default_value_t = false, help = "Distribute additional spaces between words so wrapped lines fill the maximum specified via --max-line-length; the last line of a paragraph and continuation lines of list items stay left-aligned")]
+    pub justify: bool,
+    #[arg(long, value_enum, default_value_t = Alignment::Left, help = "Align each output line within the maximum specified via --max-line-length by adding leading spaces; lines exceeding the maximum are left untouched")]
+    pub align: Alignment,
+    #[arg(long, default_value_t = false, help = "Collapse consecutive blank (whitespace-only) lines into a single blank line (like --max-blank-lines 1)")]
+    pub squeeze_blank: bool,
+    #[arg(long, default_value_t = false, help = "Treat form feeds as page separators: a form feed within a line ends the current output line (and the paragraph being rewrapped) and starts the next one")]
+    pub page_separator: bool,
+    #[arg(long, help = "Collapse more than the specified number of consecutive blank (whitespace-only) lines into that number of blank lines; specify 0 to remove all blank lines like --remove-blank-lines")]
+    pub max_blank_lines: Option<usize>,
+    #[arg(long, default_value_t = false, help = "Remove all blank (whitespace-only) lines; they still separate paragraphs when rewrapping and take precedence over --squeeze-blank")]
+    pub remove_blank_lines: bool,
+    #[arg(long, default_value_t = false, help = "Collapse runs of whitespaces within a line into a single space; the leading indentation is kept as-is")]
+    pub collapse_whitespace: bool,
+    #[arg(long, default_value_t = false, help = "Reduce runs of spaces after \".\", \"!\" or \"?\" within a line into a single space; the leading indentation and the padding after numbered list markers are kept as-is")]
+    pub normalize_sentence_spacing: bool,
+    #[arg(long, default_value_t = false, help = "Strip leading whitespaces from every input line; this happens before list items are detected so --preserve-list-indentation only aligns wrapped lines with the text after the list marker")]
+    pub trim_leading: bool,
+    #[arg(long, default_value_t = false, group = "numbering", help = "Prefix each output line with its right-aligned number and a tab; lines resulting from wrapping an input line get their own numbers and numbering continues across input files")]
+    pub number_lines: bool,
+    #[arg(long, default_value_t = false, group = "numbering", help = "Like --number-lines but only number lines with non-whitespace content, leaving blank lines as-is")]
+    pub number_nonblank: bool,
+    #[arg(long, default_value_t = 6, requires = "numbering", help = "Minimum width of the line numbers added via --number-lines or --number-nonblank")]
+    pub number_width: usize,
     #[arg(short, long, help = "Matches each line against the specified regex and substitutes matches with the specified --replacement")]
-    substitute_regex: Vec<String>,
+    pub substitute_regex: Vec<String>,
     #[arg(long, help = "Replacement for --substitute-regex, see https://docs.rs/regex/latest/regex/struct.Regex.html#replacement-string-syntax")]
-    replacement: Vec<String>,
-    #[arg(help = "Specifies files to read the input from (instead of stdin)")]
-    input_files: Vec<String>,
+    pub replacement: Vec<String>,
+    #[arg(long, help = "Flags for the --substitute-regex at the same position: i (case-insensitive), m (multi-line), s (allow . to match \\n) and/or l (match the pattern and insert the replacement literally)")]
+    pub substitute_flags: Vec<String>,
+    #[arg(long, help = "Maximum number of substitutions for the --substitute-regex at the same position; specify 0 to replace all matches (defaults to 1 as substitutions are applied per line, and to 0 when --substitute-multiline is used where the count applies to the whole input)")]
+    pub substitute_count: Vec<usize>,
+    #[arg(long, help = "Read additional substitution rules from the specified file; each line contains a regex and its replacement separated by a tab, blank lines and lines starting with # are ignored")]
+    pub substitute_file: Option<String>,
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["substitute_multiline", "in_place", "output_dir", "check", "diff"], help = "Write a JSON object for each substitution rule that changed a line to the specified file (one per line as it happens) containing the input file, the line number within it, the index of the rule and the line before and after applying the rule; input files are then formatted sequentially")]
+    pub substitution_log: Option<String>,
+    #[arg(long, default_value_t = false, help = "Read the whole input first and apply --substitute-regex to all of it (so matches may span multiple lines) replacing all matches instead of only the first match per line")]
+    pub substitute_multiline: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "substitute_multiline", help = "Omit lines that only consist of whitespaces after applying --substitute-regex (lines that have been blank before are kept)")]
+    pub drop_emptied_lines: bool,
+    #[arg(long, default_value_t = false, help = "Treat suspicious substitution rules as errors, e.g. more --replacement than --substitute-regex arguments (which is otherwise only a warning) or replacements referring to capture groups the regex does not define")]
+    pub strict: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "strict", help = "Skip substitution rules whose regex does not compile (printing a warning) instead of aborting")]
+    pub skip_invalid_rules: bool,
+    #[arg(long, value_enum, default_value_t = Encoding::Strict, help = "How to deal with input that is not valid UTF-8")]
+    pub encoding: Encoding,
+    #[arg(long, value_enum, help = "Convert the case of the content (after substitutions and before measuring the line length)")]
+    pub transform_case: Option<Case>,
+    #[arg(long, default_value_t = 0, help = "Expand tabs to spaces up to the next multiple of the specified number of columns; specify 0 to leave tabs untouched")]
+    pub tab_width: usize,
+    #[arg(long, default_value_t = 0, help = "Convert each run of the specified number of spaces within the leading indentation of input lines into a tab (after expanding tabs via --tab-width); specify 0 to leave spaces untouched")]
+    pub spaces_to_tabs: usize,
+    #[arg(long, value_enum, default_value_t = LineEnding::Lf, help = "Which line ending to use for the output")]
+    pub line_ending: LineEnding,
+    #[arg(long, default_value_t = false, help = "Ensure the output ends with exactly one line ending by removing trailing blank lines and adding a missing final line ending (unless the output is empty)")]
+    pub ensure_final_newline: bool,
+    #[arg(short = '0', long, default_value_t = false, help = "Split the input into records terminated by NUL instead of lines and terminate output lines with NUL (overriding --line-ending); newlines within a record are treated as whitespaces")]
+    pub null: bool,
+    #[arg(short, long, default_value_t = false, help = "Write the output back to the input files instead of stdout")]
+    pub in_place: bool,
+    #[arg(long, requires = "in_place", help = "Keep a copy of each input file with the specified suffix appended when using --in-place")]
+    pub backup_suffix: Option<String>,
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["in_place", "check", "diff", "output_format", "output"], help = "Write the output for each input file (except \"-\") to a file of the same name within the specified directory (which is created if needed) instead of stdout")]
+    pub output_dir: Option<String>,
+    #[arg(long, default_value_t = false, conflicts_with = "in_place", help = "Only check whether the input is already formatted; report inputs that are not on stderr instead of writing any output")]
+    pub check: bool,
+    #[arg(long, default_value_t = false, conflicts_with = "in_place", help = "Print a unified diff between each input and its formatted version instead of the formatted version itself")]
+    pub diff: bool,
+    #[arg(short, long, default_value_t = 1, help = "Format the specified number of input files concurrently (each file is formatted on its own then); specify 0 to use the number of available CPUs")]
+    pub jobs: usize,
+    #[arg(long, default_value_t = false, help = "Print statistics about the formatting (lines and bytes read/written, wraps, words exceeding --max-line-length and substitutions) as key=value pairs to stderr when done")]
+    pub stats: bool,
+    #[arg(long, default_value_t = false, help = "Print a warning with the input line number to stderr for each word that exceeds --max-line-length and cannot be broken")]
+    pub warn_long_words: bool,
+    #[arg(short, long, default_value_t = false, conflicts_with_all = ["verbose", "warn_long_words"], help = "Do not print errors and warnings to stderr; the exit code still indicates failures")]
+    pub quiet: bool,
+    #[arg(short, long, default_value_t = false, help = "Print the path of each input file to stderr when starting to format it")]
+    pub verbose: bool,
+    #[arg(long = "format", value_enum, default_value_t = OutputFormat::Text, conflicts_with_all = ["in_place", "check", "diff"], help = "How to write the output; input files are not formatted concurrently when writing JSON so a single array is written")]
+    pub output_format: OutputFormat,
+    #[arg(long, default_value_t = false, overrides_with = "separate", help = "Treat the input files as one continuous document so a paragraph at the end of one file is continued by the next file when rewrapping (the default)")]
+    pub concatenate: bool,
+    #[arg(long, visible_alias = "flush-between-files", default_value_t = false, overrides_with = "concatenate", help = "End the paragraph at the end of each input file when rewrapping (always the case when formatting in-place or with --jobs)")]
+    pub separate: bool,
+    #[arg(help = "Specifies files to read the input from (instead of stdin); specify \"-\" to read from stdin at that position")]
+    pub input_files: Vec<String>,
 }
 
-struct LineState<'a> {
-    current_char: char,
-    output_line: &'a mut String,
-    has_last_word_end: bool,
-    has_word: bool,
-    last_word_end: usize,
-    list_indentation: String,
-    has_list_indentation: bool,
-    list_padding_end: bool,
-    is_at_word_boundary: bool,
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_line_length: 0,
+            wrap_tolerance: 0,
+            max_words: 0,
+            first_line_width: None,
+            truncate: false,
+            ellipsis: None,
+            width_mode: WidthMode::Chars,
+            ansi: AnsiMode::Keep,
+            break_words: false,
+            break_on_hyphens: false,
+            extra_break_chars: String::new(),
+            wrap_after: String::new(),
+            no_break_chars: "\u{a0}\u{2007}\u{202f}".to_owned(),
+            keep_trailing_whitespaces: false,
+            trim_trailing: false,
+            preserve_list_indentation: false,
+            list_markers: String::from("*-"),
+            normalize_list_indentation: false,
+            preserve_blockquotes: false,
+            markdown: false,
+            comment_prefix: None,
+            hanging_indent: 0,
+            label_indent: None,
+            wrap_if: None,
+            detect_tables: false,
+            no_wrap_if: None,
+            strip_prefix: None,
+            verbatim_delimiter: None,
+            lines: None,
+            byte_range: None,
+            indent: 0,
+            indent_string: None,
+            prefix: None,
+            suffix: None,
+            right_margin: 0,
+            rewrap: false,
+            rewrap_respect_indent: false,
+            rewrap_keep_sentences: false,
+            sentence_spacing: 1,
+            rewrap_min_length: 0,
+            preserve_indented_code: false,
+            indented_code_width: 4,
+            justify: false,
+            align: Alignment::Left,
+            squeeze_blank: false,
+            page_separator: false,
+            max_blank_lines: None,
+            remove_blank_lines: false,
+            collapse_whitespace: false,
+            normalize_sentence_spacing: false,
+            trim_leading: false,
+            number_lines: false,
+            number_nonblank: false,
+            number_width: 6,
+            substitute_regex: Vec::new(),
+            replacement: Vec::new(),
+            substitute_flags: Vec::new(),
+            substitute_count: Vec::new(),
+            substitute_file: None,
+            substitution_log: None,
+            substitute_multiline: false,
+            drop_emptied_lines: false,
+            strict: false,
+            skip_invalid_rules: false,
+            encoding: Encoding::Strict,
+            transform_case: None,
+            tab_width: 0,
+            spaces_to_tabs: 0,
+            line_ending: LineEnding::Lf,
+            ensure_final_newline: false,
+            null: false,
+            in_place: false,
+            backup_suffix: None,
+            output_dir: None,
+            check: false,
+            diff: false,
+            jobs: 1,
+            stats: false,
+            warn_long_words: false,
+            quiet: false,
+            verbose: false,
+            output_format: OutputFormat::Text,
+            concatenate: false,
+            separate: false,
+            input_files: Vec::new(),
+        }
+    }
 }
 
-fn write_line(output: &mut dyn Write, line: &String, args: &Cli) {
-    if args.keep_trailing_whitespaces {
-        write!(output, "{}\n", line).unwrap();
-    } else {
-        write!(output, "{}\n", line.trim_end()).unwrap();
+impl Config {
+    /// Checks the config for errors that would otherwise only be returned when formatting
+    ///
+    /// This covers contradicting options (like a --right-margin exceeding the --max-line-length) and
+    /// substitution rules that cannot be compiled (including the ones read from the --substitute-file).
+    /// Nothing is printed; problems that do not prevent formatting (like an --align without
+    /// --max-line-length or rules skipped via --skip-invalid-rules) are returned as warnings instead.
+    pub fn validate(&self) -> Result<Vec<ConfigError>, ConfigError> {
+        let mut warnings = Vec::new();
+        validate_config(self, &mut warnings)?;
+        compile_substitution_rules(self, &mut warnings)?;
+        Ok(warnings)
     }
 }
 
-fn is_list_start(c: char) -> bool {
-    c == '*' || c == '-'
+/// A problem with the options of a `Config`, see `Config::validate`
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A regex specified for substitution could not be parsed
+    Regex { regex: String, error: regex::Error },
+    /// The flags specified for a regex contain an unknown flag
+    RegexFlags { flags: String, flag: char },
+    /// The file specified via --substitute-file could not be read
+    SubstitutionFile { path: String, error: io::Error },
+    /// A line of the file specified via --substitute-file could not be parsed
+    SubstitutionFileLine { path: String, line_number: usize, reason: String },
+    /// More replacements than regexes have been specified (only an error when using --strict)
+    ReplacementCount { regexes: usize, replacements: usize },
+    /// A replacement refers to a capture group its regex does not define when using --strict
+    ReplacementReference { regex: String, replacement: String, reference: String },
+    /// The right margin leaves no space for the content
+    RightMargin { right_margin: usize, max_line_length: usize },
+    /// Two input files would be written to the same file within the --output-dir
+    OutputFileCollision { path: String, other_path: String, output_path: String },
+    /// An alignment has been specified without --max-line-length (only a warning as it is ignored then)
+    AlignWithoutMaxLineLength,
+    /// Two options have been specified that cannot be used together
+    ConflictingOptions { option: &'static str, other_option: &'static str },
+    /// A substitution rule has been skipped because of the contained error via --skip-invalid-rules (only a warning)
+    SkippedRule { error: Box<ConfigError> },
 }
 
-fn flush_output_line(output: &mut dyn Write, state: &mut LineState, args: &Cli) {
-    write_line(output, &state.output_line, &args);
-    state.output_line.clear();
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Regex { regex, error } => write!(f, "Unable to parse specified regex \"{}\": {}", regex, error),
+            ConfigError::RegexFlags { flags, flag } => write!(f, "Unable to parse specified regex flags \"{}\": unknown flag '{}'", flags, flag),
+            ConfigError::SubstitutionFile { path, error } => write!(f, "Unable to read substitution rules from \"{}\": {}", path, error),
+            ConfigError::SubstitutionFileLine { path, line_number, reason } => write!(f, "Unable to parse substitution rule in \"{}\" on line {}: {}", path, line_number, reason),
+            ConfigError::ReplacementCount { regexes, replacements } => {
+                let times = |count: &usize| if *count == 1 { "1 time".to_owned() } else { format!("{} times", count) };
+                write!(f, "--replacement given {} but --substitute-regex only {}; the extra replacements are not used", times(replacements), times(regexes))
+            }
+            ConfigError::ReplacementReference { regex, replacement, reference } => write!(f, "Replacement \"{}\" refers to capture group \"{}\" which is not defined by regex \"{}\"", replacement, reference, regex),
+            ConfigError::RightMargin { right_margin, max_line_length } => write!(f, "The right margin of {} columns does not leave any space within the max line length of {} columns", right_margin, max_line_length),
+            ConfigError::OutputFileCollision { path, other_path, output_path } => write!(f, "Input files \"{}\" and \"{}\" would both be written to \"{}\"", other_path, path, output_path),
+            ConfigError::AlignWithoutMaxLineLength => write!(f, "Ignoring --align as no --max-line-length has been specified"),
+            ConfigError::ConflictingOptions { option, other_option } => write!(f, "--{} cannot be used together with --{}", option, other_option),
+            ConfigError::SkippedRule { error } => write!(f, "Skipping invalid substitution rule: {}", error),
+        }
+    }
 }
 
-fn handle_overflow(output: &mut dyn Write, state: &mut LineState, args: &Cli) -> bool {
-    // skip if there is no overflow
-    if args.max_line_length == 0 || state.output_line.len() < args.max_line_length {
-        return false;
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::SubstitutionFile { error, .. } => Some(error),
+            ConfigError::Regex { error, .. } => Some(error),
+            ConfigError::SkippedRule { error } => Some(error),
+            ConfigError::RegexFlags { .. }
+            | ConfigError::SubstitutionFileLine { .. }
+            | ConfigError::ReplacementCount { .. }
+            | ConfigError::ReplacementReference { .. }
+            | ConfigError::RightMargin { .. }
+            | ConfigError::OutputFileCollision { .. }
+            | ConfigError::AlignWithoutMaxLineLength
+            | ConfigError::ConflictingOptions { .. } => None,
+        }
     }
+}
 
-    // deal with overflow
-    if args.break_words || state.is_at_word_boundary {
-        // print the output line we have so far and write further characters into a new/clear output line
-        write_line(output, &state.output_line, &args);
-        state.output_line.clear();
-    } else if state.has_last_word_end {
-        // print the output line we have so far but only until the last whitespace; keep further characters
-        // the output line for the next line
-        let output_line_until_last_whitespace: String = state.output_line.drain(..state.last_word_end + 1).collect();
-        write_line(output, &output_line_until_last_whitespace, &args);
+/// An error that occurred while formatting
+#[derive(Debug)]
+pub enum FormatError {
+    /// Reading the input or writing the output failed
+    Io(io::Error),
+    /// The config is invalid
+    Config(ConfigError),
+    /// An input file could not be opened
+    FileOpen { path: String, error: io::Error },
+    /// An input file could not be read when formatting it in-place
+    FileRead { path: String, error: io::Error },
+    /// An input file could not be written when formatting it in-place
+    FileWrite { path: String, error: io::Error },
+    /// The output file could not be created or written
+    OutputFile { path: String, error: io::Error },
+    /// An input is not formatted when using --check
+    NotFormatted { path: String },
+    /// The file specified via --substitution-log could not be written
+    SubstitutionLog { path: String, error: io::Error },
+}
+
+impl FormatError {
+    /// Returns whether the error concerns only a single input file so formatting of other files can continue
+    fn concerns_single_file(&self) -> bool {
+        matches!(self, FormatError::FileOpen { .. } | FormatError::FileRead { .. } | FormatError::FileWrite { .. } | FormatError::NotFormatted { .. })
     }
-    state.has_last_word_end = false;
+}
 
-    // repeat list indentation on the next line if present
-    if state.has_list_indentation {
-        state.output_line.insert_str(0, state.list_indentation.as_str());
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::Io(error) => write!(f, "Unable to read input or write output: {}", error),
+            FormatError::Config(error) => write!(f, "{}", error),
+            FormatError::FileOpen { path, error } => write!(f, "Unable to open \"{}\": {}", path, error),
+            FormatError::FileRead { path, error } => write!(f, "Unable to read \"{}\": {}", path, error),
+            FormatError::FileWrite { path, error } => write!(f, "Unable to write \"{}\": {}", path, error),
+            FormatError::OutputFile { path, error } => write!(f, "Unable to write output to \"{}\": {}", path, error),
+            FormatError::NotFormatted { path } => write!(f, "\"{}\" is not formatted", path),
+            FormatError::SubstitutionLog { path, error } => write!(f, "Unable to write substitution log to \"{}\": {}", path, error),
+        }
     }
+}
 
-    // continue with next character if the overflow happened at a word-boundary (no need to repeat the whitespace)
-    if state.is_at_word_boundary {
-        state.has_last_word_end = false;
-        return true;
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FormatError::Io(error)
+            | FormatError::FileOpen { error, .. }
+            | FormatError::FileRead { error, .. }
+            | FormatError::FileWrite { error, .. }
+            | FormatError::OutputFile { error, .. }
+            | FormatError::SubstitutionLog { error, .. } => Some(error),
+            FormatError::Config(error) => Some(error),
+            FormatError::NotFormatted { .. } => None,
+        }
     }
+}
 
-    false
+impl From<io::Error> for FormatError {
+    fn from(error: io::Error) -> Self {
+        FormatError::Io(error)
+    }
 }
 
-fn handle_list(state: &mut LineState, args: &Cli) -> bool {
-    let list_found = args.preserve_list_indentation && !state.has_word && !state.has_list_indentation && is_list_start(state.current_char);
-    if list_found {
-        state.has_list_indentation = true;
-        state.list_indentation = state.output_line.clone();
-        state.list_indentation.push(' ');
-        state.list_padding_end = false;
+impl From<ConfigError> for FormatError {
+    fn from(error: ConfigError) -> Self {
+        FormatError::Config(error)
     }
-    list_found
 }
 
-fn handle_word_boundary(state: &mut LineState, _args: &Cli) {
-    if state.is_at_word_boundary {
-        state.last_word_end = state.output_line.len();
-        state.has_last_word_end = true;
-    } else {
-        state.has_word = true;
+enum SubstitutionPattern {
+    Regex(Regex),
+    Literal(String),
+}
+
+struct SubstitutionRule {
+    pattern: SubstitutionPattern,
+    replacement: String,
+    count: Option<usize>,
+}
+
+/// The counters returned by `format_with_stats` (and printed via --stats)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of input lines read
+    pub lines_read: usize,
+    /// The number of output lines written
+    pub lines_written: usize,
+    /// The number of times a line has been broken
+    pub wraps: usize,
+    /// The number of words exceeding the maximum line length
+    pub long_words: usize,
+    /// The number of matches replaced via substitution rules
+    pub substitutions: usize,
+    /// The number of bytes read
+    pub bytes_read: usize,
+    /// The number of bytes written
+    pub bytes_written: usize,
+}
+
+impl std::ops::AddAssign for Stats {
+    fn add_assign(&mut self, other: Self) {
+        self.lines_read += other.lines_read;
+        self.lines_written += other.lines_written;
+        self.wraps += other.wraps;
+        self.long_words += other.long_words;
+        self.substitutions += other.substitutions;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
     }
 }
 
-fn add_list_indentation(state: &mut LineState, list_found: bool, _args: &Cli) {
-    if  state.has_list_indentation && !list_found && !state.list_padding_end {
-        if state.is_at_word_boundary {
-            state.list_indentation.push(state.current_char);
-        } else {
-            state.list_padding_end = true;
-        }
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "lines_read={}", self.lines_read)?;
+        writeln!(f, "lines_written={}", self.lines_written)?;
+        writeln!(f, "wraps={}", self.wraps)?;
+        writeln!(f, "long_words={}", self.long_words)?;
+        writeln!(f, "substitutions={}", self.substitutions)?;
+        writeln!(f, "bytes_read={}", self.bytes_read)?;
+        writeln!(f, "bytes_written={}", self.bytes_written)
     }
 }
 
-fn is_new_paragraph_c(c: char) -> bool {
-    c.is_control() || is_list_start(c)
+/// A writer counting the bytes written to the underlying writer for --stats
+struct CountingWriter<W: Write> {
+    inner: W,
+    bytes_written: usize,
 }
 
-fn is_new_paragraph(s: &String) -> bool {
-    for c in s.chars() {
-        if !c.is_whitespace() {
-            return is_new_paragraph_c(c);
-        }
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = self.inner.write(buf)?;
+        self.bytes_written += bytes_written;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
-    true
 }
 
-fn handle_next_line<'a>(output: &mut dyn Write, mut input_line: &'a mut String, output_line_: &mut String, args: &Cli, substitute_regex: &Vec<Regex>) {
-    let mut state = LineState{
-        current_char: '\0',
-        output_line: output_line_,
-        has_last_word_end: false,
-        has_word: false,
-        last_word_end: 0,
-        list_indentation: String::new(),
-        has_list_indentation: false,
-        list_padding_end: true,
-        is_at_word_boundary: false,
-    };
+struct OutputState<W: Write> {
+    writer: CountingWriter<W>,
+    line_ending: &'static str,
+    pending_line_ending: Option<&'static str>,
+    has_final_line_ending: bool,
+    line_number: usize,
+    indentation: String,
+    stats: Stats,
+    // the number and byte offsets of the current input line and where the line written next originates from (for
+    // --format json)
+    input_line_number: usize,
+    input_byte_range: std::ops::Range<usize>,
+    source_line_number: usize,
+    is_wrapped: bool,
+    json_lines: Vec<serde_json::Value>,
+    // the file changes of substitution rules are written to for --substitution-log
+    substitution_log: Option<SubstitutionLog>,
+    // the path of the current input (for diagnostics), the number of input lines preceding it and the warnings
+    // about it not reported so far
+    input_path: String,
+    input_path_line_offset: usize,
+    warnings: Vec<String>,
+    // whether the line written next is within a block of --verbatim-delimiter or outside of --lines/--byte-range
+    // (so it is written without any decorations)
+    is_verbatim: bool,
+    is_unselected: bool,
+    held_back_blank_lines: usize,
+}
 
-    // flush previous line in rewrapping mode if the current line is a new paragraph/list-item
-    if args.rewrap && !state.output_line.is_empty() && is_new_paragraph(&input_line) {
-        flush_output_line(output, &mut state, &args);
+impl<W: Write> OutputState<W> {
+    fn new(writer: W, args: &Config) -> Self {
+        OutputState {
+            writer: CountingWriter { inner: writer, bytes_written: 0 },
+            line_ending: match args.line_ending {
+                _ if args.null => "\0",
+                LineEnding::Crlf => "\r\n",
+                _ => "\n",
+            },
+            pending_line_ending: None,
+            has_final_line_ending: true,
+            line_number: 0,
+            indentation: args.indent_string.clone().unwrap_or_else(|| " ".repeat(args.indent)),
+            stats: Stats::default(),
+            input_line_number: 0,
+            input_byte_range: 0..0,
+            source_line_number: 0,
+            is_wrapped: false,
+            json_lines: Vec::new(),
+            substitution_log: None,
+            input_path: String::from("<stdin>"),
+            input_path_line_offset: 0,
+            warnings: Vec::new(),
+            is_verbatim: false,
+            is_unselected: false,
+            held_back_blank_lines: 0,
+        }
     }
 
-    // apply substitute_regex
-    let substituted_line: &mut String = &mut input_line;
-    for pair in substitute_regex.iter().zip_longest(&args.replacement) {
-        match pair {
-            Both(regex, replacement) => { *substituted_line = String::from(regex.replace(&substituted_line, replacement)); },
-            Left(regex) => { *substituted_line = String::from(regex.replace(&substituted_line, "")); },
-            Right(_) => {},
-        };
+    /// Takes note that further input lines are read from the specified input file (or "-" for stdin)
+    fn start_input(&mut self, path: &str) {
+        self.input_path = if path == "-" { String::from("<stdin>") } else { path.to_owned() };
+        self.input_path_line_offset = self.input_line_number;
     }
 
-    // insert a whitespace on underflow when rewrapping and trim input
-    let mut input_iter = substituted_line.chars();
-    if args.rewrap && !state.output_line.is_empty() {
-        state.output_line.push(' ');
-        input_iter = substituted_line.trim_start().chars();
+    /// Reports the warnings about the current input noted so far
+    fn report_warnings(&mut self, args: &Config) {
+        for warning in self.warnings.drain(..) {
+            report(args, &warning);
+        }
     }
 
-    for c in input_iter {
-        state.current_char = c;
-        state.is_at_word_boundary = c.is_whitespace();
+    /// Returns the stats so far including the number of bytes written
+    fn stats(&self) -> Stats {
+        Stats { bytes_written: self.writer.bytes_written, ..self.stats }
+    }
+}
 
-        // handle the case when the current line is full
-        if handle_overflow(output, &mut state, args) {
-            continue;
+#[derive(Default)]
+struct ParagraphState {
+    line: LineState,
+    item_indentation: Option<String>,
+    line_prefix: String,
+    blank_line_count: usize,
+    is_verbatim: bool,
+    indentation_width: usize,
+}
+
+#[derive(Default)]
+struct LineState {
+    // the output line, whether it continues a list item and whether it has just been wrapped (so further
+    // whitespaces must not be carried over to it) are kept across input lines when rewrapping
+    output_line: String,
+    is_list_continuation: bool,
+    is_wrapped: bool,
+    // the input line the output line starts in and whether the output line results from wrapping
+    source_line_number: usize,
+    has_wrapped: bool,
+    // the byte index of the most recent char of --wrap-after within the output line
+    last_preferred_break: Option<usize>,
+    // the indentation of the text following a --label-indent match at the beginning of the output line
+    label_indentation: String,
+    // the number of words within the output line (only counted for --max-words)
+    word_count: usize,
+    // the remaining fields are reset for each input line (keeping the allocated buffers)
+    current_char: char,
+    has_last_word_end: bool,
+    has_word: bool,
+    last_word_end: usize,
+    output_line_width: usize,
+    list_indentation: String,
+    has_list_indentation: bool,
+    list_padding_end: bool,
+    numbered_list_marker_len: usize,
+    list_marker_remaining: usize,
+    line_prefix: String,
+    is_at_word_boundary: bool,
+    is_at_word_start: bool,
+    is_in_url: bool,
+    // the number of backticks of the Markdown code span the current char is in (0 if none), whether it is
+    // in a link target and the number of chars of the current backtick run following the current char
+    code_span_backticks: usize,
+    is_in_link_target: bool,
+    backticks_remaining: usize,
+    is_in_markdown_span: bool,
+    is_in_long_word: bool,
+    is_truncated: bool,
+    current_width: usize,
+    ansi_parser: AnsiParser,
+}
+
+impl LineState {
+    fn reset(&mut self, args: &Config) {
+        self.current_char = '\0';
+        self.has_last_word_end = false;
+        self.has_word = false;
+        self.last_word_end = 0;
+        self.output_line_width = str_width(&self.output_line, args);
+        self.list_indentation.clear();
+        self.has_list_indentation = false;
+        self.list_padding_end = true;
+        self.numbered_list_marker_len = 0;
+        self.list_marker_remaining = 0;
+        self.line_prefix.clear();
+        self.is_at_word_boundary = false;
+        self.is_at_word_start = false;
+        self.is_in_url = false;
+        self.code_span_backticks = 0;
+        self.is_in_link_target = false;
+        self.backticks_remaining = 0;
+        self.is_in_markdown_span = false;
+        self.is_in_long_word = false;
+        self.is_truncated = false;
+        self.current_width = 0;
+        self.ansi_parser = AnsiParser::default();
+    }
+}
+
+fn write_line(output: &mut OutputState<impl Write>, line: &str, args: &Config) -> io::Result<()> {
+    // hold back blank lines so they can be omitted at the end with --ensure-final-newline
+    if args.ensure_final_newline {
+        if line.trim().is_empty() {
+            output.held_back_blank_lines += 1;
+            return Ok(());
         }
+        for _ in 0..std::mem::take(&mut output.held_back_blank_lines) {
+            write_output_line(output, "", args)?;
+        }
+    }
+    write_output_line(output, line, args)
+}
 
-        // take note of lists and word boundaries
-        let list_found = handle_list(&mut state, &args);
-        handle_word_boundary(&mut state, &args);
+fn write_output_line(output: &mut OutputState<impl Write>, line: &str, args: &Config) -> io::Result<()> {
+    output.stats.lines_written += 1;
+    let is_numbered = !output.is_unselected && (args.number_lines || (args.number_nonblank && !line.trim().is_empty()));
+    if is_numbered {
+        output.line_number += 1;
+    }
+    let line_number = is_numbered.then_some(output.line_number);
+    if args.output_format == OutputFormat::Json {
+        // collect the lines to write them as JSON array in the end
+        let mut content = Vec::new();
+        write_line_content(&mut content, line_number, &output.indentation, output.is_verbatim, output.is_unselected, line, args)?;
+        output.json_lines.push(serde_json::json!({
+            "source_line": output.source_line_number,
+            "output": String::from_utf8_lossy(&content),
+            "wrapped": output.is_wrapped,
+        }));
+        return Ok(());
+    }
 
-        // add the current character to current line
-        state.output_line.push(c);
+    // terminate the previous line; the terminator is only written when another line follows so it
+    // can be omitted at the end if the input lacks a final line ending
+    if let Some(line_ending) = output.pending_line_ending.take() {
+        output.writer.write_all(line_ending.as_bytes())?;
+    }
+    write_line_content(&mut output.writer, line_number, &output.indentation, output.is_verbatim, output.is_unselected, line, args)?;
+    output.pending_line_ending = Some(output.line_ending);
+    Ok(())
+}
 
-        // add the current character to list indentation
-        add_list_indentation(&mut state, list_found, &args);
+/// Writes the specified input line as-is (except for decorations)
+fn write_unwrapped_line(output: &mut OutputState<impl Write>, line: &str, args: &Config) -> io::Result<()> {
+    output.source_line_number = output.input_line_number;
+    output.is_wrapped = false;
+    write_line(output, line, args)
+}
+
+/// Writes the specified line with its decorations (but without terminator); unselected lines are written as-is
+fn write_line_content(writer: &mut impl Write, line_number: Option<usize>, indentation: &str, is_verbatim: bool, is_unselected: bool, line: &str, args: &Config) -> io::Result<()> {
+    if is_unselected {
+        return writer.write_all(line.as_bytes());
+    }
+    let keeps_trailing_whitespaces = is_verbatim || (args.keep_trailing_whitespaces && !args.trim_trailing);
+    if let Some(line_number) = line_number {
+        write!(writer, "{:>width$}\t", line_number, width = args.number_width)?;
+    }
+    // indent non-empty or decorated lines (leaving empty lines without trailing whitespace)
+    let is_decorated = args.prefix.is_some() || args.suffix.is_some();
+    if is_decorated || (keeps_trailing_whitespaces && !is_verbatim) || !line.trim_end().is_empty() {
+        writer.write_all(indentation.as_bytes())?;
     }
+    if let Some(prefix) = &args.prefix {
+        writer.write_all(prefix.as_bytes())?;
+    }
+    let mut line = line;
+    let mut padding = 0;
+    let max_line_length = available_line_length(args);
+    if args.align != Alignment::Left && max_line_length > 0 && !is_verbatim {
+        // pad the content (without leading and trailing whitespaces) with leading spaces only
+        line = line.trim_start();
+        let content_width = str_width(line.trim_end(), args);
+        if content_width > 0 && content_width < max_line_length {
+            padding = max_line_length - content_width;
+            padding = if args.align == Alignment::Center { padding / 2 } else { padding };
+            write!(writer, "{:padding$}", "")?;
+        }
+    }
+    if !keeps_trailing_whitespaces {
+        line = line.trim_end();
+    }
+    writer.write_all(line.as_bytes())?;
+    if let Some(suffix) = &args.suffix {
+        // align the suffix at the end of the line
+        let width = padding + str_width(line, args);
+        if width < max_line_length {
+            write!(writer, "{:1$}", "", max_line_length - width)?;
+        }
+        writer.write_all(suffix.as_bytes())?;
+    }
+    Ok(())
+}
 
-    // flush current output line
-    if !args.rewrap {
-        flush_output_line(output, &mut state, &args);
+/// Writes the output line which is kept back while rewrapping as the paragraph might continue
+fn flush_paragraph(output: &mut OutputState<impl Write>, paragraph: &mut ParagraphState, args: &Config) -> io::Result<()> {
+    if args.rewrap && !paragraph.line.output_line.is_empty() {
+        flush_output_line(output, &mut paragraph.line, args)?;
     }
+    Ok(())
 }
 
-fn read_lines<R: BufRead>(output: &mut dyn Write, input: R, output_line: &mut String, args: &Cli, substitute_regex: &Vec<Regex>) {
-    for line in input.lines() {
-        handle_next_line(output, &mut line.unwrap(), output_line, &args, &substitute_regex);
+/// Writes what is still pending, returning the line ending of the last line if it is omitted as the input lacks it
+fn finish_output(output: &mut OutputState<impl Write>, paragraph: &mut ParagraphState, args: &Config) -> io::Result<Option<&'static str>> {
+    // print the last output line
+    flush_paragraph(output, paragraph, args)?;
+    if args.output_format == OutputFormat::Json {
+        serde_json::to_writer(&mut output.writer, &output.json_lines)?;
+        output.json_lines.clear();
+        return output.writer.write_all(b"\n").map(|()| None);
+    }
+    output.held_back_blank_lines = 0;
+    match output.pending_line_ending.take() {
+        Some(line_ending) if output.has_final_line_ending || args.ensure_final_newline => output.writer.write_all(line_ending.as_bytes()).map(|()| None),
+        line_ending => Ok(line_ending),
     }
 }
 
-fn read_lines_from_input_or_files(output: &mut dyn Write, input: &mut dyn BufRead, args: &Cli) -> i32 {
-    // parse regex for substitution
-    let mut substitute_regex = Vec::new();
-    for regex in &args.substitute_regex {
-        match Regex::new(&regex) {
-            Ok(regex) => {
-                substitute_regex.push(regex);
-            }
-            Err(error) => {
-                eprintln!("Unable parse specified regex \"{}\": {}", regex, error);
-                return 1;
-            }
-        };
+fn char_width(c: char, args: &Config) -> usize {
+    match args.width_mode {
+        WidthMode::Chars => 1,
+        WidthMode::Display | WidthMode::Grapheme => c.width().unwrap_or(0),
+        WidthMode::Bytes => c.len_utf8(),
     }
+}
 
-    // read input line-by-line and echo a formatted version of the input
-    let mut exit_code: i32 = 0;
-    let mut output_line = String::new();
-    if args.input_files.is_empty() {
-        read_lines(output, input, &mut output_line, &args, &substitute_regex);
-    } else {
-        for input_file_path in &args.input_files {
-            let mut input_file_reader = match File::open(input_file_path) {
-                Ok(input_file) => BufReader::new(input_file),
-                Err(error) => {
-                    eprintln!("Unable to open \"{}\": {}", input_file_path, error);
-                    exit_code = 1;
-                    continue;
-                }
-            };
-            read_lines(output, &mut input_file_reader, &mut output_line, &args, &substitute_regex);
-        }
+/// Returns the width of the specified char or grapheme cluster (with --width-mode grapheme)
+fn segment_width(segment: &str, args: &Config) -> usize {
+    match args.width_mode {
+        WidthMode::Grapheme => segment.width(),
+        _ => segment.chars().map(|c| char_width(c, args)).sum(),
     }
+}
 
-    // print the last output line
-    if args.rewrap {
-        write_line(output, &output_line, &args);
+/// Splits `s` into the units lines are broken between: grapheme clusters with --width-mode grapheme, chars otherwise
+fn segments<'a>(s: &'a str, args: &Config) -> impl Iterator<Item = &'a str> {
+    segment_indices(s, args).map(|(_, segment)| segment)
+}
+
+/// Like `segments` but also returns the byte index of each segment
+fn segment_indices<'a>(s: &'a str, args: &Config) -> impl Iterator<Item = (usize, &'a str)> {
+    if args.width_mode == WidthMode::Grapheme {
+        itertools::Either::Left(s.grapheme_indices(true))
+    } else {
+        itertools::Either::Right(s.char_indices().map(move |(index, c)| (index, &s[index..index + c.len_utf8()])))
     }
+}
 
-    exit_code
+fn starts_with_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
 }
 
-pub fn run(output: &mut dyn Write, input: &mut dyn BufRead) -> i32 {
-    read_lines_from_input_or_files(output, input, &Cli::parse())
+fn str_width(s: &str, args: &Config) -> usize {
+    if args.ansi == AnsiMode::Keep {
+        return segments(s, args).map(|segment| segment_width(segment, args)).sum();
+    }
+    let mut ansi_parser = AnsiParser::default();
+    segments(s, args).filter(|segment| !segment.chars().all(|c| ansi_parser.is_escape_sequence(c))).map(|segment| segment_width(segment, args)).sum()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::{Read, Cursor, Seek, SeekFrom};
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum AnsiParser {
+    #[default]
+    Text,
+    Escape,
+    ControlSequence,
+}
 
-    fn test_read_lines(expected: &[u8], input_data: &[u8], args: &Cli) {
-        let mut input = Cursor::new(Vec::new());
-        let mut output = Cursor::new(Vec::new());
+impl AnsiParser {
+    /// Returns whether `c` is part of an ANSI escape sequence, e.g. "\x1b[31m", taking previous characters into account
+    fn is_escape_sequence(&mut self, c: char) -> bool {
+        *self = match (*self, c) {
+            (AnsiParser::Text, '\x1b') => AnsiParser::Escape,
+            (AnsiParser::Text, _) => return false,
+            (AnsiParser::Escape, '[') => AnsiParser::ControlSequence,
+            (AnsiParser::ControlSequence, '\x40'..='\x7e') | (AnsiParser::Escape, _) => AnsiParser::Text,
+            (AnsiParser::ControlSequence, _) => AnsiParser::ControlSequence,
+        };
+        true
+    }
+}
 
-        // write some test data
-        input.write_all(&input_data).unwrap();
-        input.seek(SeekFrom::Start(0)).unwrap();
+/// Returns the maximum line length reduced by the width of the indentation, prefix and suffix or 0 if the line length is unlimited
+fn available_line_length(args: &Config) -> usize {
+    available_width(args.max_line_length, args)
+}
 
-        // read the test data
-        read_lines_from_input_or_files(&mut output, &mut input, &args);
+/// Returns the specified line length reduced by the width of the indentation, prefix and suffix or 0 if it is unlimited
+fn available_width(line_length: usize, args: &Config) -> usize {
+    if line_length == 0 {
+        return 0;
+    }
+    let indentation_width = args.indent_string.as_ref().map_or(args.indent, |indent_string| str_width(indent_string, args));
+    let decoration_width = [&args.prefix, &args.suffix].into_iter().flatten().map(|decoration| str_width(decoration, args)).sum::<usize>();
+    line_length.saturating_sub(indentation_width + decoration_width + args.right_margin).max(1)
+}
 
-        // check the output
-        let mut result = Vec::new();
-        output.seek(SeekFrom::Start(0)).unwrap();
-        output.read_to_end(&mut result).unwrap();
-        assert_eq!(String::from_utf8(expected.to_vec()).unwrap(), String::from_utf8(result).unwrap());
+/// Returns the width available for the current output line (using --first-line-width if it does not result from wrapping)
+fn output_line_budget(state: &LineState, args: &Config) -> usize {
+    match args.first_line_width {
+        Some(first_line_width) if !state.has_wrapped => available_width(first_line_width, args),
+        _ => available_line_length(args),
     }
+}
 
-    #[test]
-    fn test_simple_one_liner() {
-        let mk_args = ||
-            Cli{ max_line_length: 0, break_words: true, keep_trailing_whitespaces: true, preserve_list_indentation: false, rewrap: false, substitute_regex: Vec::new(), replacement: Vec::new(), input_files: Vec::new() };
-        test_read_lines(b"foo\n", b"foo\n", &mk_args());
+fn expand_tabs(line: &str, args: &Config) -> String {
+    let mut expanded_line = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = args.tab_width - column % args.tab_width;
+            expanded_line.extend(std::iter::repeat_n(' ', spaces));
+            column += spaces;
+        } else {
+            expanded_line.push(c);
+            column += char_width(c, args);
+        }
     }
+    expanded_line
+}
 
-    #[test]
-    fn test_line_wrapping_with_word_breaks() {
-        let mk_args = |max_line_length_: usize, keep_trailing_whitespaces_: bool|
-            Cli{ max_line_length: max_line_length_, break_words: true, keep_trailing_whitespaces: keep_trailing_whitespaces_, preserve_list_indentation: false, rewrap: false, substitute_regex: Vec::new(), replacement: Vec::new(), input_files: Vec::new() };
-        test_read_lines(b"foo bar ba\nz\n", b"foo bar baz\n", &mk_args(10, false));
-        test_read_lines(b"foo bar ba\nz\n", b"foo bar baz\n", &mk_args(10, true));
-        test_read_lines(b"fo\no\nba\nr\nba\nz\n", b"foo bar baz\n", &mk_args(2, false));
-        test_read_lines(b"fo\no \nba\nr \nba\nz\n", b"foo bar baz\n", &mk_args(2, true));
-        test_read_lines(b"fooba\nr\nbaz\n", b"foobar\nbaz\n", &mk_args(5, false));
+fn spaces_to_tabs(line: &str, args: &Config) -> String {
+    let indentation_len = line.len() - line.trim_start().len();
+    let mut converted_line = String::with_capacity(line.len());
+    let mut spaces = 0;
+    let push_spaces = |converted_line: &mut String, spaces: usize| {
+        converted_line.extend(std::iter::repeat_n('\t', spaces / args.spaces_to_tabs));
+        converted_line.extend(std::iter::repeat_n(' ', spaces % args.spaces_to_tabs));
+    };
+    for c in line[..indentation_len].chars() {
+        if c == ' ' {
+            spaces += 1;
+        } else {
+            push_spaces(&mut converted_line, std::mem::take(&mut spaces));
+            converted_line.push(c);
+        }
     }
+    push_spaces(&mut converted_line, spaces);
+    converted_line.push_str(&line[indentation_len..]);
+    converted_line
+}
 
-    #[test]
-    fn test_line_wrapping_without_work_breaks() {
-        let mk_args = |max_line_length_: usize, keep_trailing_whitespaces_: bool|
-            Cli{ max_line_length: max_line_length_, break_words: false, keep_trailing_whitespaces: keep_trailing_whitespaces_, preserve_list_indentation: false, rewrap: false, substitute_regex: Vec::new(), replacement: Vec::new(), input_files: Vec::new() };
-        test_read_lines(b"foo bar\nbaz\n", b"foo bar baz\n", &mk_args(10, false));
-        test_read_lines(b"foo bar \nbaz\n", b"foo bar baz\n", &mk_args(10, true));
-        test_read_lines(b"foo\nbar\nbaz\n", b"foo bar baz\n", &mk_args(2, false));
-        test_read_lines(b"foobar\nbaz\nt1 t2\n", b"foobar\nbaz t1 t2\n", &mk_args(5, false));
+fn collapse_whitespace(line: &str) -> String {
+    let content_start = line.len() - line.trim_start().len();
+    let mut collapsed_line = String::with_capacity(line.len());
+    collapsed_line.push_str(&line[..content_start]);
+    let mut previous_whitespace = false;
+    for c in line[content_start..].chars() {
+        let is_whitespace = c.is_whitespace();
+        if !is_whitespace {
+            collapsed_line.push(c);
+        } else if !previous_whitespace {
+            collapsed_line.push(' ');
+        }
+        previous_whitespace = is_whitespace;
     }
+    collapsed_line
+}
 
-    #[test]
-    fn test_list_handling_without_preserving_indentation() {
-        let mk_args = |max_line_length_: usize|
-            Cli{ max_line_length: max_line_length_, break_words: false, keep_trailing_whitespaces: false, preserve_list_indentation: false, rewrap: false, substitute_regex: Vec::new(), replacement: Vec::new(), input_files: Vec::new() };
-        test_read_lines(b"A list\nfollows:\n* foo bar baz\n* test1 test2\ntest3 test4\n", b"A list follows:\n* foo bar baz\n* test1 test2 test3 test4\n", &mk_args(14));
-        test_read_lines(b"A list\nfollows:\n* foo bar baz\n* test1 test2\ntest3 test4\n", b"A list follows:\n* foo bar baz\n* test1 test2 test3 test4\n", &mk_args(13));
+fn normalize_sentence_spacing(line: &str) -> String {
+    let content = line.trim_start();
+    let marker_end = line.len() - content.len() + numbered_list_marker_len(content);
+    let content_start = line.len() - line[marker_end..].trim_start().len();
+    let mut normalized_line = String::with_capacity(line.len());
+    normalized_line.push_str(&line[..content_start]);
+    let mut previous_char = '\0';
+    for c in line[content_start..].chars() {
+        if c != ' ' || previous_char != ' ' || !normalized_line.trim_end_matches(' ').ends_with(['.', '!', '?']) {
+            normalized_line.push(c);
+        }
+        previous_char = c;
     }
+    normalized_line
+}
 
-    #[test]
-    fn test_list_handling_with_preserving_indentation() {
-        let mk_args = |max_line_length_: usize|
-            Cli{ max_line_length: max_line_length_, break_words: false, keep_trailing_whitespaces: false, preserve_list_indentation: true, rewrap: false, substitute_regex: Vec::new(), replacement: Vec::new(), input_files: Vec::new() };
-        test_read_lines(b"A list\nfollows:\n* foo bar baz\n* test1 test2\n  test3 test4\n", b"A list follows:\n* foo bar baz\n* test1 test2 test3 test4\n", &mk_args(13));
-        test_read_lines(b"A list\nfollows:\n* foo bar baz\n  * test1\n    test2\n    test3\n    test4\n", b"A list follows:\n* foo bar baz\n  * test1 test2 test3 test4\n", &mk_args(13));
-        test_read_lines(b"A list follows:\n* foo bar baz\n  * test1 test2\n    test3 test4\n", b"A list follows:\n* foo bar baz\n  * test1 test2 test3 test4\n", &mk_args(15));
+fn is_word_boundary(c: char, args: &Config) -> bool {
+    c.is_whitespace() && !args.no_break_chars.contains(c)
+}
+
+fn is_list_start(c: char, args: &Config) -> bool {
+    args.list_markers.contains(c)
+}
+
+/// Converts the case of `s`; words are separated by whitespaces for the title case
+fn transform_case(s: &str, case: Case) -> String {
+    match case {
+        Case::Upper => s.to_uppercase(),
+        Case::Lower => s.to_lowercase(),
+        Case::Title => {
+            let mut result = String::with_capacity(s.len());
+            let mut is_at_word_start = true;
+            for c in s.chars() {
+                if is_at_word_start {
+                    // approximate the title case of chars with multi-char uppercase mappings, e.g. "ß" becomes "Ss"
+                    let mut upper = c.to_uppercase();
+                    result.extend(upper.next());
+                    result.extend(upper.flat_map(char::to_lowercase));
+                } else {
+                    result.extend(c.to_lowercase());
+                }
+                is_at_word_start = c.is_whitespace();
+            }
+            result
+        }
     }
+}
 
-    #[test]
-    fn test_rewrapping() {
-        let mk_args = |max_line_length_: usize|
-            Cli{ max_line_length: max_line_length_, break_words: false, keep_trailing_whitespaces: false, preserve_list_indentation: true, rewrap: true, substitute_regex: Vec::new(), replacement: Vec::new(), input_files: Vec::new() };
-        test_read_lines(b"A list follows:\n* foo bar baz\n  * test1 test2\n    test3 test4\n", b"A list follows:\n* foo bar baz\n  * test1 test2 test3 test4\n", &mk_args(15));
-        test_read_lines(b"A list follows:\n* foo bar baz\n  * test1 test2\n    test3 test4\n", b"A list\nfollows:\n* foo\n  bar baz\n  * test1 test2 test3 test4\n", &mk_args(15));
-        test_read_lines(b"A list follows:\n* foo bar baz\n  * test1 test2 test3 test4\n", b"A list\nfollows:\n* foo\n  bar baz\n  * test1 test2 test3 test4\n", &mk_args(0));
+/// Returns the number of chars of the marker if `s` starts with a numbered list marker like "1." or "10)"
+fn numbered_list_marker_len(s: &str) -> usize {
+    let digits = s.chars().take_while(char::is_ascii_digit).count();
+    let mut rest = s[digits..].chars();
+    match (rest.next(), rest.next()) {
+        (Some('.' | ')'), Some(c)) if digits > 0 && c.is_whitespace() => digits + 1,
+        _ => 0,
     }
+}
 
-    #[test]
-    fn test_reading_input_files() {
-        let input_file_paths = Vec::from([String::from("testfiles/testinput1"), String::from("testfiles/testinput2")]);
-        let mk_args = |max_line_length_: usize|
-        Cli{ max_line_length: max_line_length_, break_words: false, keep_trailing_whitespaces: false, preserve_list_indentation: true, rewrap: true, substitute_regex: Vec::new(), replacement: Vec::new(), input_files: input_file_paths };
-        test_read_lines(b"foo bar 1 2 3 4\n5 6 7 8 9 10 11\n12\n", b"", &mk_args(15));
+fn flush_output_line(output: &mut OutputState<impl Write>, state: &mut LineState, args: &Config) -> io::Result<()> {
+    output.source_line_number = state.source_line_number;
+    output.is_wrapped = state.has_wrapped;
+    write_line(output, &state.output_line, args)?;
+    state.output_line.clear();
+    state.output_line_width = 0;
+    state.is_list_continuation = false;
+    state.is_wrapped = false;
+    state.has_wrapped = false;
+    state.last_preferred_break = None;
+    state.label_indentation.clear();
+    state.word_count = 0;
+    Ok(())
+}
+
+/// Widens the spaces between the words of the specified wrapped `line` so it fills the maximum line length
+///
+/// The line prefix and the indentation (including the list marker) are kept as-is. Lines with only a single
+/// word or lines that are already too long are returned unchanged.
+fn justify_line(line: &str, state: &LineState, args: &Config) -> String {
+    let line = line.trim_end();
+    let indentation = if state.has_list_indentation {
+        let indentation_chars = state.line_prefix.chars().count() + state.list_indentation.chars().count();
+        line.char_indices().nth(indentation_chars).map_or(line.len(), |(index, _)| index)
+    } else {
+        let prefix_len = state.line_prefix.len().min(line.len());
+        prefix_len + (line[prefix_len..].len() - line[prefix_len..].trim_start().len())
+    };
+    let (indentation, content) = line.split_at(indentation);
+    let words: Vec<&str> = content.split(|c| is_word_boundary(c, args)).filter(|word| !word.is_empty()).collect();
+    let gaps = words.len().saturating_sub(1);
+    let fixed_width = str_width(indentation, args) + words.iter().map(|word| str_width(word, args)).sum::<usize>();
+    let max_line_length = output_line_budget(state, args);
+    if gaps == 0 || fixed_width + gaps >= max_line_length {
+        return line.to_owned();
+    }
+    let spaces = max_line_length - fixed_width;
+    let mut justified_line = String::with_capacity(line.len() + spaces);
+    justified_line.push_str(indentation);
+    for (index, word) in words.iter().enumerate() {
+        if index > 0 {
+            let gap_width = spaces / gaps + usize::from(index <= spaces % gaps);
+            justified_line.extend(std::iter::repeat_n(' ', gap_width));
+        }
+        justified_line.push_str(word);
     }
+    justified_line
+}
 
-    #[test]
-    fn test_substitution() {
-        let mk_args = |_substitute_regex: Vec<String>, _replacement: Vec<String>|
-        Cli{ max_line_length: 20, break_words: false, keep_trailing_whitespaces: false, preserve_list_indentation: true, rewrap: false, substitute_regex: _substitute_regex, replacement: _replacement, input_files: Vec::new() };
-        test_read_lines(b"f00bar\nf00baz\n", b"foobar\nfoobaz\n", &mk_args(vec!["oo".to_owned(), "remove".to_owned()], vec!["00".to_owned()]));
+fn write_wrapped_line(output: &mut OutputState<impl Write>, line: &str, state: &LineState, args: &Config) -> io::Result<()> {
+    output.source_line_number = state.source_line_number;
+    output.is_wrapped = true;
+    if args.justify && !state.is_list_continuation {
+        write_line(output, &justify_line(line, state, args), args)
+    } else {
+        write_line(output, line, args)
+    }
+}
+
+/// Cuts the output line so it fits into `max_line_length` columns including the --ellipsis
+fn truncate_line(state: &mut LineState, max_line_length: usize, args: &Config) {
+    let ellipsis = args.ellipsis.as_deref().unwrap_or_default();
+    let ellipsis_width = str_width(ellipsis, args);
+    if state.output_line_width + ellipsis_width > max_line_length {
+        // cut before the first segment exceeding the space left for the ellipsis (not counting ANSI escape sequences)
+        let budget = max_line_length.saturating_sub(ellipsis_width);
+        let mut width = 0;
+        let mut ansi_parser = AnsiParser::default();
+        let cut = segment_indices(&state.output_line, args).find(|(_, segment)| {
+            if args.ansi != AnsiMode::Keep && segment.chars().all(|c| ansi_parser.is_escape_sequence(c)) {
+                return false;
+            }
+            width += segment_width(segment, args);
+            width > budget
+        });
+        if let Some((index, _)) = cut {
+            state.output_line.truncate(index);
+        }
+    }
+    if !ellipsis.is_empty() {
+        state.output_line.truncate(state.output_line.trim_end().len());
+        state.output_line.push_str(ellipsis);
+    }
+    state.output_line_width = str_width(&state.output_line, args);
+}
+
+fn handle_overflow(output: &mut OutputState<impl Write>, state: &mut LineState, args: &Config) -> io::Result<bool> {
+    // skip further characters of a truncated line
+    if state.is_truncated {
+        return Ok(true);
+    }
+
+    // skip if there is no overflow (neither of the line length nor of the number of words)
+    let max_line_length = output_line_budget(state, args);
+    let exceeds_max_words = args.max_words > 0 && state.is_at_word_start && state.word_count >= args.max_words;
+    if !exceeds_max_words && (max_line_length == 0 || state.output_line_width + state.current_width <= max_line_length + args.wrap_tolerance) {
+        return Ok(false);
+    }
+
+    // cut the line instead of breaking it when truncating
+    if args.truncate {
+        truncate_line(state, max_line_length, args);
+        state.is_truncated = true;
+        return Ok(true);
+    }
+
+    // deal with overflow (never breaking URLs within)
+    let mut is_current_char_skipped = state.is_at_word_boundary;
+    if let Some(preferred_break) = state.last_preferred_break.take() {
+        // print the output line we have so far but only until the last preferred break point; keep further
+        // characters (except leading whitespaces) in the output line for the next line
+        let break_len = preferred_break + state.output_line[preferred_break..].chars().next().map_or(0, char::len_utf8);
+        write_wrapped_line(output, &state.output_line[..break_len], state, args)?;
+        state.output_line.drain(..break_len);
+        let whitespace_len = state.output_line.len() - state.output_line.trim_start().len();
+        state.output_line.drain(..whitespace_len);
+        state.output_line_width = str_width(&state.output_line, args);
+        is_current_char_skipped &= state.output_line.is_empty();
+    } else if (args.break_words && !state.is_in_url && !state.is_in_markdown_span) || state.is_at_word_boundary {
+        // print the output line we have so far and write further characters into a new/clear output line
+        write_wrapped_line(output, &state.output_line, state, args)?;
+        state.output_line.clear();
+        state.output_line_width = 0;
+    } else if state.has_last_word_end {
+        // print the output line we have so far but only until the last whitespace; keep further characters
+        // the output line for the next line
+        // note: last_word_end is the byte index of the whitespace so take its UTF-8 length into account
+        let break_len = state.last_word_end + state.output_line[state.last_word_end..].chars().next().map_or(0, char::len_utf8);
+        write_wrapped_line(output, &state.output_line[..break_len], state, args)?;
+        state.output_line_width -= str_width(&state.output_line[..break_len], args);
+        state.output_line.drain(..break_len);
+    } else {
+        // keep the unbreakable word on the current line (taking note of it once)
+        if !state.is_in_long_word {
+            state.is_in_long_word = true;
+            output.stats.long_words += 1;
+            if args.warn_long_words {
+                let line_number = output.input_line_number - output.input_path_line_offset;
+                output.warnings.push(format!("\"{}\" line {} contains a word exceeding the maximum line length", output.input_path, line_number));
+            }
+        }
+        return Ok(false);
+    }
+    state.has_last_word_end = false;
+    state.is_in_long_word = false;
+    state.is_list_continuation = state.has_list_indentation;
+    state.source_line_number = output.input_line_number;
+    state.has_wrapped = true;
+    output.stats.wraps += 1;
+    if args.max_words > 0 {
+        state.word_count = state.output_line.split(|c| is_word_boundary(c, args)).filter(|word| !word.is_empty()).count();
+    }
+
+    // repeat list indentation (or add the hanging indentation) and the line prefix on the next line if present
+    if state.has_list_indentation {
+        state.output_line.insert_str(0, state.list_indentation.as_str());
+        state.output_line_width += str_width(&state.list_indentation, args);
+    } else if args.hanging_indent > 0 {
+        state.output_line.insert_str(0, &" ".repeat(args.hanging_indent));
+        state.output_line_width += args.hanging_indent;
+    }
+    if !state.line_prefix.is_empty() {
+        state.output_line.insert_str(0, state.line_prefix.as_str());
+        state.output_line_width += str_width(&state.line_prefix, args);
+    }
+
+    // continue with next character if the overflow happened at a word-boundary (no need to repeat the whitespace)
+    if is_current_char_skipped {
+        state.has_last_word_end = false;
+        state.is_wrapped = true;
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+fn preserves_list_indentation(args: &Config) -> bool {
+    args.preserve_list_indentation || args.normalize_list_indentation
+}
+
+fn is_list_item(s: &str, args: &Config) -> bool {
+    let content = s.trim_start();
+    content.starts_with(|c| is_list_start(c, args)) || numbered_list_marker_len(content) > 0
+}
+
+fn handle_list(state: &mut LineState, args: &Config) -> bool {
+    let list_found = preserves_list_indentation(args) && !state.has_word && !state.has_list_indentation
+        && (is_list_start(state.current_char, args) || (state.numbered_list_marker_len > 0 && !state.is_at_word_boundary));
+    if list_found {
+        // replace the list marker with spaces; subsequent chars of a numbered list marker are skipped
+        // when adding further padding
+        let marker_len = state.numbered_list_marker_len.max(1);
+        state.has_list_indentation = true;
+        state.list_indentation.clone_from(&state.output_line);
+        state.list_indentation.extend(std::iter::repeat_n(' ', marker_len));
+        state.list_marker_remaining = marker_len - 1;
+        state.list_padding_end = false;
+    }
+    list_found
+}
+
+fn handle_word_boundary(state: &mut LineState, args: &Config) {
+    if state.is_at_word_start && args.max_words > 0 {
+        state.word_count += 1;
+    }
+    if state.is_at_word_boundary {
+        state.last_word_end = state.output_line.len();
+        state.has_last_word_end = true;
+        state.is_in_long_word = false;
+    } else {
+        // allow breaking after a hyphen/slash or extra break char within a word (but not after a list marker);
+        // breaking includes the char at last_word_end so the char stays on the first line
+        let is_break_char = (args.break_on_hyphens && matches!(state.current_char, '-' | '/')) || args.extra_break_chars.contains(state.current_char);
+        if is_break_char && state.has_word && !state.is_in_url && !state.is_in_markdown_span
+            && state.output_line.chars().next_back().is_some_and(|c| !c.is_whitespace()) {
+            state.last_word_end = state.output_line.len();
+            state.has_last_word_end = true;
+        }
+        state.has_word = true;
+    }
+    if args.wrap_after.contains(state.current_char) && !state.is_in_url && !state.is_in_markdown_span {
+        state.last_preferred_break = Some(state.output_line.len());
+    }
+}
+
+/// Takes note whether the current char (followed by `rest`) is within a Markdown code span or link target
+fn handle_markdown_spans(state: &mut LineState, rest: &str) {
+    let was_in_span = state.code_span_backticks > 0 || state.is_in_link_target || state.backticks_remaining > 0;
+    if state.backticks_remaining > 0 {
+        state.backticks_remaining -= 1;
+    } else if state.current_char == '`' {
+        // a code span ends with a backtick run of the same length it started with
+        let backticks = rest.len() - rest.trim_start_matches('`').len();
+        state.backticks_remaining = backticks - 1;
+        if state.code_span_backticks == 0 && !state.is_in_link_target {
+            state.code_span_backticks = backticks;
+        } else if state.code_span_backticks == backticks {
+            state.code_span_backticks = 0;
+        }
+    } else if state.is_in_link_target {
+        state.is_in_link_target = state.current_char != ')';
+    } else if state.code_span_backticks == 0 {
+        state.is_in_link_target = rest.starts_with("](");
+    }
+    state.is_in_markdown_span = was_in_span || state.code_span_backticks > 0 || state.is_in_link_target;
+}
+
+fn add_list_indentation(state: &mut LineState, list_found: bool, _args: &Config) {
+    if  state.has_list_indentation && !list_found && !state.list_padding_end {
+        if state.list_marker_remaining > 0 {
+            state.list_marker_remaining -= 1;
+        } else if state.is_at_word_boundary {
+            state.list_indentation.push(state.current_char);
+        } else {
+            state.list_padding_end = true;
+        }
+    }
+}
+
+fn is_new_paragraph_c(c: char, args: &Config) -> bool {
+    c.is_control() || is_list_start(c, args)
+}
+
+/// Returns the blockquote prefix `s` starts with, e.g. "> > " for "> > foo"
+fn blockquote_prefix(s: &str) -> &str {
+    let mut end = 0;
+    for (index, c) in s.char_indices() {
+        if c == '>' {
+            end = index + 1;
+        } else if !c.is_whitespace() {
+            break;
+        }
+    }
+    if end > 0 && s[end..].starts_with(' ') {
+        end += 1;
+    }
+    &s[..end]
+}
+
+fn blockquote_depth(prefix: &str) -> usize {
+    prefix.matches('>').count()
+}
+
+/// Returns the --comment-prefix `s` starts with including indentation and a following space, e.g. "  // " for "  // foo"
+fn comment_prefix<'a>(s: &'a str, args: &Config) -> &'a str {
+    let Some(comment_prefix) = &args.comment_prefix else { return "" };
+    let content = s.trim_start();
+    if comment_prefix.is_empty() || !content.starts_with(comment_prefix.as_str()) {
+        return "";
+    }
+    let mut end = s.len() - content.len() + comment_prefix.len();
+    if s[end..].starts_with(' ') {
+        end += 1;
+    }
+    &s[..end]
+}
+
+/// Returns the prefix to repeat on each line of the paragraph `s` is part of, consisting of the comment and blockquote prefix
+fn line_prefix<'a>(s: &'a str, args: &Config) -> &'a str {
+    let mut end = comment_prefix(s, args).len();
+    if args.preserve_blockquotes {
+        end += blockquote_prefix(&s[end..]).len();
+    }
+    &s[..end]
+}
+
+fn is_blank_line(s: &str, args: &Config) -> bool {
+    s[line_prefix(s, args).len()..].trim().is_empty()
+}
+
+/// Returns the width of the indentation of `s` after its comment/blockquote prefix including a list marker if present
+fn indentation_width(s: &str, args: &Config) -> usize {
+    let s = &s[line_prefix(s, args).len()..];
+    let content = s.trim_start();
+    let marker_len = match content.chars().next() {
+        Some(c) if is_list_start(c, args) => c.len_utf8(),
+        _ => numbered_list_marker_len(content),
+    };
+    let text = if marker_len > 0 { content[marker_len..].trim_start() } else { content };
+    str_width(&s[..s.len() - text.len()], args)
+}
+
+fn ends_sentence(s: &str) -> bool {
+    let s = s.trim_end().trim_end_matches(['"', '\'', ')', ']', '\u{201D}', '\u{2019}']);
+    s.ends_with(['.', '!', '?'])
+}
+
+/// Returns whether `s` looks like a table row when using --detect-tables
+///
+/// Runs of spaces following sentence-ending punctuation do not count as column separators so prose using two
+/// spaces after sentences is not mistaken for a table.
+fn is_table_row(s: &str, args: &Config) -> bool {
+    if !args.detect_tables {
+        return false;
+    }
+    let content = s.trim();
+    if content.len() > 1 && ((content.starts_with('|') && content.ends_with('|')) || (content.starts_with('+') && content.ends_with('+') && content.contains('-'))) {
+        return true;
+    }
+    let mut column_separators = 0;
+    let mut rest = content;
+    while let Some(index) = rest.find("  ") {
+        column_separators += usize::from(!rest[..index].ends_with(['.', '!', '?']));
+        rest = rest[index..].trim_start_matches(' ');
+    }
+    column_separators >= 2
+}
+
+/// Returns whether `s` is indented code when using --preserve-indented-code
+fn is_indented_code(s: &str, args: &Config) -> bool {
+    let content = s.trim_start();
+    args.preserve_indented_code && !content.is_empty() && str_width(&s[..s.len() - content.len()], args) >= args.indented_code_width
+}
+
+fn is_new_paragraph(s: &str, paragraph_prefix: &str, args: &Config) -> bool {
+    let mut content = s;
+    let mut paragraph_prefix = paragraph_prefix;
+    if args.comment_prefix.is_some() {
+        let prefix = comment_prefix(s, args);
+        let paragraph_comment_prefix = comment_prefix(paragraph_prefix, args);
+        if prefix.is_empty() != paragraph_comment_prefix.is_empty() {
+            return true;
+        }
+        content = &s[prefix.len()..];
+        paragraph_prefix = &paragraph_prefix[paragraph_comment_prefix.len()..];
+    }
+    if args.preserve_blockquotes {
+        let prefix = blockquote_prefix(content);
+        if blockquote_depth(prefix) != blockquote_depth(paragraph_prefix) {
+            return true;
+        }
+        content = &content[prefix.len()..];
+    }
+    if args.page_separator && content.trim_start_matches([' ', '\t']).starts_with('\x0c') {
+        return true;
+    }
+    let content = content.trim_start();
+    content.chars().next().is_none_or(|c| is_new_paragraph_c(c, args)) || numbered_list_marker_len(content) > 0
+}
+
+/// Substitutes the first match of `regex` in `line` with `replacement` using `scratch` as buffer
+///
+/// Leaves `line` untouched if there is no match. Otherwise the contents of `line` and `scratch` are
+/// swapped so the buffers are reused for further substitutions.
+fn substitute(line: &mut String, regex: &Regex, replacement: &str, scratch: &mut String) -> usize {
+    scratch.clear();
+    if replacement.contains('$') {
+        let Some(captures) = regex.captures(line) else { return 0 };
+        let whole_match = captures.get(0).unwrap();
+        scratch.push_str(&line[..whole_match.start()]);
+        captures.expand(replacement, scratch);
+        scratch.push_str(&line[whole_match.end()..]);
+    } else {
+        let Some(whole_match) = regex.find(line) else { return 0 };
+        scratch.push_str(&line[..whole_match.start()]);
+        scratch.push_str(replacement);
+        scratch.push_str(&line[whole_match.end()..]);
+    }
+    std::mem::swap(line, scratch);
+    1
+}
+
+fn substitute_literal(line: &mut String, pattern: &str, replacement: &str, scratch: &mut String) -> usize {
+    let Some(start) = line.find(pattern) else { return 0 };
+    scratch.clear();
+    scratch.push_str(&line[..start]);
+    scratch.push_str(replacement);
+    scratch.push_str(&line[start + pattern.len()..]);
+    std::mem::swap(line, scratch);
+    1
+}
+
+/// Replaces up to `count` matches of `rule` within `text`; a `count` of 0 means all matches
+fn replace_matches<'t>(text: &'t str, pattern: &SubstitutionPattern, replacement: &str, count: usize) -> Cow<'t, str> {
+    match pattern {
+        SubstitutionPattern::Regex(regex) => regex.replacen(text, count, replacement),
+        SubstitutionPattern::Literal(pattern) if !text.contains(pattern.as_str()) => Cow::Borrowed(text),
+        SubstitutionPattern::Literal(pattern) if count == 0 => Cow::Owned(text.replace(pattern.as_str(), replacement)),
+        SubstitutionPattern::Literal(pattern) => Cow::Owned(text.replacen(pattern.as_str(), replacement, count)),
+    }
+}
+
+/// Returns the number of matches `replace_matches` replaces
+fn count_matches(text: &str, pattern: &SubstitutionPattern, count: usize) -> usize {
+    let limit = if count == 0 { usize::MAX } else { count };
+    match pattern {
+        SubstitutionPattern::Regex(regex) => regex.find_iter(text).take(limit).count(),
+        SubstitutionPattern::Literal(pattern) => text.matches(pattern.as_str()).take(limit).count(),
+    }
+}
+
+/// Replaces up to `count` matches of `pattern` within `text` returning the number of replaced matches
+fn replace_and_count_matches(text: &mut String, pattern: &SubstitutionPattern, replacement: &str, count: usize) -> usize {
+    let Cow::Owned(replaced) = replace_matches(text, pattern, replacement, count) else { return 0 };
+    let match_count = count_matches(text, pattern, count);
+    *text = replaced;
+    match_count
+}
+
+/// Applies the substitution rules to the specified line returning the number of replaced matches
+fn apply_substitutions(line: &mut String, substitution_rules: &[SubstitutionRule], scratch: &mut String) -> usize {
+    substitution_rules.iter().map(|rule| match (&rule.pattern, rule.count.unwrap_or(1)) {
+        (SubstitutionPattern::Regex(regex), 1) => substitute(line, regex, &rule.replacement, scratch),
+        (SubstitutionPattern::Literal(pattern), 1) => substitute_literal(line, pattern, &rule.replacement, scratch),
+        (pattern, count) => replace_and_count_matches(line, pattern, &rule.replacement, count),
+    }).sum()
+}
+
+/// Like `apply_substitutions` but writes a record to the --substitution-log for each rule that actually changes the line
+fn apply_and_log_substitutions(output: &mut OutputState<impl Write>, line: &mut String, substitution_rules: &[SubstitutionRule]) -> usize {
+    let mut substitutions = 0;
+    for (index, rule) in substitution_rules.iter().enumerate() {
+        let count = rule.count.unwrap_or(1);
+        let Cow::Owned(replaced) = replace_matches(line, &rule.pattern, &rule.replacement, count) else { continue };
+        substitutions += count_matches(line, &rule.pattern, count);
+        if replaced != *line {
+            let line_number = output.input_line_number - output.input_path_line_offset;
+            if let Some(log) = &mut output.substitution_log {
+                log.write_record(&serde_json::json!({
+                    "path": output.input_path,
+                    "line": line_number,
+                    "rule": index,
+                    "before": line,
+                    "after": replaced,
+                }));
+            }
+        }
+        *line = replaced;
+    }
+    substitutions
+}
+
+/// The file the records of the --substitution-log are written to as newline-delimited JSON
+///
+/// Records are written as they occur so the log is also present when formatting fails. The first error
+/// writing the log is kept and returned by `finish` so formatting is not interrupted by it.
+struct SubstitutionLog {
+    path: String,
+    writer: BufWriter<File>,
+    error: Option<io::Error>,
+}
+
+impl SubstitutionLog {
+    fn create(path: &str) -> Result<Self, FormatError> {
+        let file = File::create(path).map_err(|error| FormatError::SubstitutionLog { path: path.to_owned(), error })?;
+        Ok(SubstitutionLog { path: path.to_owned(), writer: BufWriter::new(file), error: None })
+    }
+
+    fn write_record(&mut self, record: &serde_json::Value) {
+        if self.error.is_none() {
+            let result = serde_json::to_writer(&mut self.writer, record).map_err(io::Error::from).and_then(|()| self.writer.write_all(b"\n"));
+            self.error = result.err();
+        }
+    }
+
+    fn finish(mut self) -> Result<(), FormatError> {
+        let result = match self.error.take() {
+            Some(error) => Err(error),
+            None => self.writer.flush(),
+        };
+        result.map_err(|error| FormatError::SubstitutionLog { path: self.path, error })
+    }
+}
+
+fn apply_substitutions_to_all_matches(text: &mut String, substitution_rules: &[SubstitutionRule]) -> usize {
+    substitution_rules.iter().map(|rule| replace_and_count_matches(text, &rule.pattern, &rule.replacement, rule.count.unwrap_or(0))).sum()
+}
+
+fn handle_next_line(output: &mut OutputState<impl Write>, input_line: &mut String, paragraph: &mut ParagraphState, args: &Config, substitution_rules: &[SubstitutionRule], scratch: &mut String) -> io::Result<()> {
+    let state = &mut paragraph.line;
+    state.reset(args);
+
+    // remove the --strip-prefix (only when matching at the beginning of the line)
+    if let Some(prefix_len) = args.strip_prefix.as_ref().and_then(|regex| regex.find(input_line)).filter(|prefix| prefix.start() == 0).map(|prefix| prefix.end()) {
+        input_line.drain(..prefix_len);
+    }
+
+    // pass lines not matching --wrap-if and table rows through as-is, ending the paragraph being rewrapped
+    if args.wrap_if.as_ref().is_some_and(|wrap_if| !wrap_if.is_match(input_line)) || is_table_row(input_line, args) {
+        if !state.output_line.is_empty() {
+            flush_output_line(output, state, args)?;
+        }
+        paragraph.item_indentation = None;
+        return write_unwrapped_line(output, input_line, args);
+    }
+
+    // process lines matching --no-wrap-if (without breaking them) and indented code on their own
+    let is_unbreakable = args.no_wrap_if.as_ref().is_some_and(|no_wrap_if| no_wrap_if.is_match(input_line));
+    let is_code = is_indented_code(input_line, args);
+    if (is_unbreakable || is_code) && !state.output_line.is_empty() {
+        flush_output_line(output, state, args)?;
+    }
+
+    // apply substitute_regex, omitting the line if it is emptied by it
+    let substituted_line: &mut String = input_line;
+    let is_blank_before_substitution = args.drop_emptied_lines && substituted_line.trim().is_empty();
+    output.stats.substitutions += if output.substitution_log.is_some() {
+        apply_and_log_substitutions(output, substituted_line, substitution_rules)
+    } else {
+        apply_substitutions(substituted_line, substitution_rules, scratch)
+    };
+    if args.drop_emptied_lines && !is_blank_before_substitution && substituted_line.trim().is_empty() {
+        return Ok(());
+    }
+    if let Some(case) = args.transform_case {
+        *substituted_line = transform_case(substituted_line, case);
+    }
+
+    // flush previous line in rewrapping mode if the (substituted) current line is a new paragraph/list-item; emit
+    // blank lines as-is so paragraphs stay separated
+    if args.rewrap {
+        if !state.output_line.is_empty() && (is_new_paragraph(substituted_line, &paragraph.line_prefix, args)
+            || (args.rewrap_respect_indent && indentation_width(substituted_line, args) != paragraph.indentation_width)
+            || (args.rewrap_keep_sentences && ends_sentence(&state.output_line))
+            || (args.rewrap_min_length > 0 && state.output_line_width >= args.rewrap_min_length)) {
+            flush_output_line(output, state, args)?;
+        }
+        if is_blank_line(substituted_line, args) {
+            paragraph.item_indentation = None;
+            state.is_list_continuation = false;
+            return write_unwrapped_line(output, substituted_line, args);
+        }
+        if args.rewrap_respect_indent && state.output_line.is_empty() {
+            paragraph.indentation_width = indentation_width(substituted_line, args);
+        }
+    }
+
+    // take note where the output line starts
+    if state.output_line.is_empty() {
+        state.source_line_number = output.input_line_number;
+    }
+
+    // collapse whitespaces after the indentation
+    if args.collapse_whitespace {
+        *substituted_line = collapse_whitespace(substituted_line);
+    }
+
+    // reduce the spacing after sentences
+    if args.normalize_sentence_spacing {
+        *substituted_line = normalize_sentence_spacing(substituted_line);
+    }
+
+    // strip the leading indentation
+    if args.trim_leading {
+        let indentation_len = substituted_line.len() - substituted_line.trim_start().len();
+        substituted_line.drain(..indentation_len);
+    }
+
+    // expand tabs so they are measured correctly
+    if args.tab_width > 0 && substituted_line.contains('\t') {
+        *substituted_line = expand_tabs(substituted_line, args);
+    }
+
+    // convert leading spaces to tabs
+    if args.spaces_to_tabs > 0 && substituted_line.starts_with(char::is_whitespace) {
+        *substituted_line = spaces_to_tabs(substituted_line, args);
+    }
+
+    // detect numbered list items at the beginning of a new output line
+    if preserves_list_indentation(args) && state.output_line.is_empty() {
+        state.numbered_list_marker_len = numbered_list_marker_len(substituted_line.trim_start());
+    }
+
+    // re-indent lines belonging to the previous list item; a blank line or a new item ends the item
+    if args.normalize_list_indentation {
+        if substituted_line.trim().is_empty() || is_list_item(substituted_line, args) {
+            paragraph.item_indentation = None;
+        } else if let Some(item_indentation) = &paragraph.item_indentation {
+            if !args.rewrap || state.output_line.is_empty() {
+                *substituted_line = format!("{}{}", item_indentation, substituted_line.trim_start());
+            }
+            state.list_indentation.clone_from(item_indentation);
+            state.has_list_indentation = true;
+            state.is_list_continuation = true;
+        }
+    }
+
+    // align continuation lines under the text following a label like "Note: "
+    if let Some(label_indent) = &args.label_indent {
+        if let Some(label) = label_indent.find(substituted_line).filter(|label| label.start() == 0 && state.output_line.is_empty()) {
+            state.label_indentation = " ".repeat(str_width(label.as_str(), args));
+        }
+        if !state.label_indentation.is_empty() && !state.has_list_indentation {
+            state.list_indentation.clone_from(&state.label_indentation);
+            state.has_list_indentation = true;
+            state.list_padding_end = true;
+        }
+    }
+
+    // repeat the indentation of code when breaking it
+    if is_code && !state.has_list_indentation {
+        let indentation_len = substituted_line.len() - substituted_line.trim_start().len();
+        state.list_indentation.push_str(&substituted_line[..indentation_len]);
+        state.has_list_indentation = true;
+    }
+
+    // take note of the comment/blockquote prefix to repeat it on further lines of the paragraph
+    let mut line_prefix_len = 0;
+    if args.preserve_blockquotes || args.comment_prefix.is_some() {
+        let line_prefix = line_prefix(substituted_line, args);
+        if state.output_line.is_empty() {
+            paragraph.line_prefix = line_prefix.to_owned();
+        }
+        line_prefix_len = line_prefix.len();
+        state.line_prefix.clone_from(&paragraph.line_prefix);
+    }
+
+    // insert a whitespace on underflow when rewrapping and trim input (including the comment/blockquote prefix
+    // which is already present)
+    let mut input = substituted_line.as_str();
+    if args.rewrap && !state.output_line.is_empty() {
+        if !state.is_wrapped {
+            // note: last_word_end is the last of the spaces so none of them end up on the next line when breaking there
+            let spacing = if args.sentence_spacing > 1 && ends_sentence(&state.output_line) { args.sentence_spacing } else { 1 };
+            state.output_line.extend(std::iter::repeat_n(' ', spacing - 1));
+            state.last_word_end = state.output_line.len();
+            state.has_last_word_end = true;
+            state.output_line.push(' ');
+            state.output_line_width += spacing;
+        }
+        input = substituted_line[line_prefix_len..].trim_start();
+    }
+
+    // process the input char-by-char (or grapheme-by-grapheme so lines are never broken within a grapheme cluster)
+    state.output_line.reserve(input.len());
+    let mut is_at_word_start = true;
+    for (index, segment) in segment_indices(input, args) {
+        let c = segment.chars().next().unwrap_or_default();
+
+        // keep/strip ANSI escape sequences without considering them any further
+        if args.ansi != AnsiMode::Keep && state.ansi_parser.is_escape_sequence(c) {
+            if args.ansi == AnsiMode::Ignore {
+                state.output_line.push_str(segment);
+            }
+            continue;
+        }
+
+        // start a new output line at a form feed
+        if args.page_separator && c == '\x0c' && !state.output_line.is_empty() {
+            flush_output_line(output, state, args)?;
+            state.reset(args);
+            state.source_line_number = output.input_line_number;
+            is_at_word_start = true;
+        }
+
+        state.current_char = c;
+        state.current_width = segment_width(segment, args);
+        state.is_at_word_boundary = is_word_boundary(c, args);
+
+        // take note of Markdown code spans and link targets which must not be broken (not even at whitespaces)
+        if args.markdown {
+            handle_markdown_spans(state, &input[index..]);
+            state.is_at_word_boundary &= !state.is_in_markdown_span;
+        }
+
+        // take note of URLs which must not be broken
+        if state.is_at_word_boundary {
+            state.is_in_url = false;
+        } else if is_at_word_start {
+            state.is_in_url = starts_with_url(&input[index..]);
+        }
+        state.is_at_word_start = is_at_word_start && !state.is_at_word_boundary;
+        is_at_word_start = state.is_at_word_boundary;
+
+        // handle the case when the current line is full; skip further whitespaces after wrapping so the next
+        // line does not start with them
+        if !is_unbreakable && handle_overflow(output, state, args)? {
+            continue;
+        }
+        if state.is_wrapped {
+            if state.is_at_word_boundary && (!state.has_list_indentation || state.list_padding_end) {
+                continue;
+            }
+            state.is_wrapped = false;
+        }
+
+        // take note of lists and word boundaries
+        let list_found = preserves_list_indentation(args) && handle_list(state, args);
+        handle_word_boundary(state, args);
+
+        // add the current character to current line
+        state.output_line.push_str(segment);
+        state.output_line_width += state.current_width;
+
+        // add the current character to list indentation
+        if state.has_list_indentation {
+            add_list_indentation(state, list_found, args);
+        }
+    }
+
+    // flush current output line
+    if !args.rewrap || is_unbreakable || is_code {
+        flush_output_line(output, state, args)?;
+    }
+
+    // keep track of the current list item to re-indent subsequent lines belonging to it
+    if args.normalize_list_indentation && state.has_list_indentation {
+        paragraph.item_indentation = Some(state.list_indentation.clone());
+    }
+    Ok(())
+}
+
+fn decode_line(line: &[u8], args: &Config) -> io::Result<String> {
+    match args.encoding {
+        Encoding::Strict => String::from_utf8(line.to_vec()).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+        Encoding::Lossy => Ok(String::from_utf8_lossy(line).into_owned()),
+    }
+}
+
+fn read_lines<R: BufRead>(output: &mut OutputState<impl Write>, mut input: R, paragraph: &mut ParagraphState, args: &Config, substitution_rules: &[SubstitutionRule]) -> Result<(), FormatError> {
+    // apply substitutions to the whole input at once and process the result line-by-line without further substitutions
+    if args.substitute_multiline && !substitution_rules.is_empty() {
+        let mut contents = Vec::new();
+        input.read_to_end(&mut contents)?;
+        let stats = Stats {
+            lines_read: output.stats.lines_read + contents.split_inclusive(|&byte| byte == b'\n').count(),
+            bytes_read: output.stats.bytes_read + contents.len(),
+            ..output.stats
+        };
+        let mut contents = decode_line(&contents, args)?;
+        output.stats.substitutions += apply_substitutions_to_all_matches(&mut contents, substitution_rules);
+        read_lines(output, Cursor::new(contents), paragraph, args, &[])?;
+        // count the lines/bytes of the input rather than the ones of the substituted input
+        output.stats.lines_read = stats.lines_read;
+        output.stats.bytes_read = stats.bytes_read;
+        return Ok(());
+    }
+
+    let mut line = Vec::new();
+    let mut scratch = String::new();
+    let terminator = if args.null { b'\0' } else { b'\n' };
+    loop {
+        // read raw bytes so invalid UTF-8 can be dealt with according to the configured encoding
+        line.clear();
+        if input.read_until(terminator, &mut line)? == 0 {
+            break;
+        }
+        output.stats.lines_read += 1;
+        output.input_byte_range = output.stats.bytes_read..output.stats.bytes_read + line.len();
+        output.stats.bytes_read += line.len();
+
+        // strip the line terminator like BufRead::lines() does, taking note of it if it should be preserved
+        output.has_final_line_ending = line.last() == Some(&terminator);
+        if output.has_final_line_ending && args.null {
+            line.pop();
+        } else if output.has_final_line_ending {
+            line.pop();
+            let is_crlf = line.last() == Some(&b'\r');
+            if is_crlf {
+                line.pop();
+            }
+            if args.line_ending == LineEnding::Preserve {
+                output.line_ending = if is_crlf { "\r\n" } else { "\n" };
+            }
+        }
+
+        process_line(output, &mut decode_line(&line, args)?, paragraph, args, substitution_rules, &mut scratch)?;
+        output.report_warnings(args);
+    }
+    Ok(())
+}
+
+/// Formats the specified input line (without terminator) taking care of blank lines
+fn process_line(output: &mut OutputState<impl Write>, line: &mut String, paragraph: &mut ParagraphState, args: &Config, substitution_rules: &[SubstitutionRule], scratch: &mut String) -> io::Result<()> {
+    output.input_line_number += 1;
+    if args.lines.is_some_and(|lines| !lines.contains(output.input_line_number))
+        || args.byte_range.as_ref().is_some_and(|byte_range| byte_range.start >= output.input_byte_range.end || byte_range.end <= output.input_byte_range.start) {
+        // pass lines outside of --lines/--byte-range through unchanged, ending the paragraph so it does not spill outside
+        output.is_unselected = true;
+        let result = write_verbatim_line(output, line, paragraph, args);
+        output.is_unselected = false;
+        return result;
+    }
+    if let Some(delimiter) = args.verbatim_delimiter.as_deref().filter(|delimiter| !delimiter.is_empty()) {
+        // pass lines within verbatim blocks (including the delimiters) through as-is, ending the paragraph
+        let is_delimiter = line.trim_start().starts_with(delimiter);
+        if is_delimiter || paragraph.is_verbatim {
+            paragraph.is_verbatim ^= is_delimiter;
+            return write_verbatim_line(output, line, paragraph, args);
+        }
+    }
+    let max_blank_lines = args.max_blank_lines.into_iter().chain(args.squeeze_blank.then_some(1)).min();
+    if (args.remove_blank_lines || max_blank_lines == Some(0)) && line.trim().is_empty() {
+        // end the paragraph as the next line would otherwise be joined with it
+        flush_paragraph(output, paragraph, args)?;
+        paragraph.item_indentation = None;
+        paragraph.line.is_list_continuation = false;
+        return Ok(());
+    }
+    if let Some(max_blank_lines) = max_blank_lines {
+        // skip blank lines following the maximum number of blank lines
+        paragraph.blank_line_count = if line.trim().is_empty() { paragraph.blank_line_count + 1 } else { 0 };
+        if paragraph.blank_line_count > max_blank_lines {
+            return Ok(());
+        }
+    }
+    handle_next_line(output, line, paragraph, args, substitution_rules, scratch)
+}
+
+/// Writes the specified input line as-is, ending the current paragraph
+fn write_verbatim_line(output: &mut OutputState<impl Write>, line: &str, paragraph: &mut ParagraphState, args: &Config) -> io::Result<()> {
+    flush_paragraph(output, paragraph, args)?;
+    paragraph.item_indentation = None;
+    paragraph.blank_line_count = 0;
+    output.is_verbatim = true;
+    let result = write_unwrapped_line(output, line, args);
+    output.is_verbatim = false;
+    result
+}
+
+fn write_file_atomically(path: &str, contents: &[u8], args: &Config) -> io::Result<()> {
+    // write to a temporary file in the same directory first and replace the original file only
+    // when everything has been written
+    let temp_path = format!("{}.{}.tmp", path, std::process::id());
+    let write_temp_file = || -> io::Result<()> {
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(contents)?;
+        temp_file.sync_all()?;
+        fs::set_permissions(&temp_path, fs::metadata(path)?.permissions())
+    };
+    if let Err(error) = write_temp_file() {
+        let _ = fs::remove_file(&temp_path);
+        return Err(error);
+    }
+    if let Some(backup_suffix) = &args.backup_suffix {
+        fs::copy(path, format!("{}{}", path, backup_suffix))?;
+    }
+    fs::rename(&temp_path, path)
+}
+
+/// An input formatted into a buffer
+#[derive(Default)]
+struct FormattedBuffer {
+    original: Vec<u8>,
+    formatted: Vec<u8>,
+    /// The line ending of the last line if it is omitted as the input lacks it (see `finish_output`)
+    omitted_line_ending: Option<&'static str>,
+}
+
+/// Formats the specified `input` on its own returning the original input and the formatted output
+fn format_to_buffer(path: &str, input: &mut dyn BufRead, args: &Config, substitution_rules: &[SubstitutionRule], stats: &mut Stats) -> Result<FormattedBuffer, FormatError> {
+    let mut original = Vec::new();
+    input.read_to_end(&mut original)?;
+    let mut buffer = Vec::new();
+    let mut output = OutputState::new(&mut buffer, args);
+    output.start_input(path);
+    let mut paragraph = ParagraphState::default();
+    read_lines(&mut output, original.as_slice(), &mut paragraph, args, substitution_rules)?;
+    let omitted_line_ending = finish_output(&mut output, &mut paragraph, args)?;
+    *stats += output.stats();
+    Ok(FormattedBuffer { original, formatted: buffer, omitted_line_ending })
+}
+
+fn format_file_to_buffer(path: &str, args: &Config, substitution_rules: &[SubstitutionRule], stats: &mut Stats) -> Result<FormattedBuffer, FormatError> {
+    let input_file = File::open(path).map_err(|error| FormatError::FileOpen { path: path.to_owned(), error })?;
+    format_to_buffer(path, &mut BufReader::new(input_file), args, substitution_rules, stats).map_err(|error| match error {
+        FormatError::Io(error) => FormatError::FileRead { path: path.to_owned(), error },
+        error => error,
+    })
+}
+
+fn format_file_in_place(path: &str, args: &Config, substitution_rules: &[SubstitutionRule], stats: &mut Stats) -> Result<(), FormatError> {
+    let buffer = format_file_to_buffer(path, args, substitution_rules, stats)?.formatted;
+    let Some(output_dir) = &args.output_dir else {
+        return write_file_atomically(path, &buffer, args).map_err(|error| FormatError::FileWrite { path: path.to_owned(), error });
+    };
+    let output_path = output_dir_path(path, output_dir);
+    fs::create_dir_all(output_dir).and_then(|()| fs::write(&output_path, &buffer))
+        .map_err(|error| FormatError::FileWrite { path: output_path.to_string_lossy().into_owned(), error })
+}
+
+/// Returns the path within the --output-dir the formatted version of the input file at `path` is written to
+fn output_dir_path(path: &str, output_dir: &str) -> std::path::PathBuf {
+    std::path::Path::new(output_dir).join(std::path::Path::new(path).file_name().unwrap_or(path.as_ref()))
+}
+
+/// Returns whether the input files are written back or into the --output-dir rather than to the output
+fn writes_input_files(config: &Config) -> bool {
+    config.in_place || config.output_dir.is_some()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DiffOp {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Computes the operations turning `original` into `formatted` via the longest common subsequence of lines
+fn diff_lines(original: &[&str], formatted: &[&str]) -> Vec<DiffOp> {
+    let columns = formatted.len() + 1;
+    let mut lengths = vec![0usize; (original.len() + 1) * columns];
+    for i in (0..original.len()).rev() {
+        for j in (0..formatted.len()).rev() {
+            lengths[i * columns + j] = if original[i] == formatted[j] {
+                lengths[(i + 1) * columns + j + 1] + 1
+            } else {
+                lengths[(i + 1) * columns + j].max(lengths[i * columns + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut ops = Vec::with_capacity(original.len() + formatted.len());
+    while i < original.len() || j < formatted.len() {
+        if i < original.len() && j < formatted.len() && original[i] == formatted[j] {
+            ops.push(DiffOp::Equal);
+            i += 1;
+            j += 1;
+        } else if i < original.len() && (j == formatted.len() || lengths[(i + 1) * columns + j] >= lengths[i * columns + j + 1]) {
+            ops.push(DiffOp::Delete);
+            i += 1;
+        } else {
+            ops.push(DiffOp::Insert);
+            j += 1;
+        }
+    }
+    ops
+}
+
+fn hunk_range(start: usize, len: usize) -> String {
+    // an empty range refers to the line before it
+    if len == 0 { format!("{},0", start) } else { format!("{},{}", start + 1, len) }
+}
+
+fn write_diff_line(output: &mut dyn Write, marker: char, line: &str) -> io::Result<()> {
+    write!(output, "{}{}", marker, line)?;
+    if !line.ends_with('\n') {
+        writeln!(output, "\n\\ No newline at end of file")?;
+    }
+    Ok(())
+}
+
+/// Writes a unified diff with 3 lines of context between `original` and `formatted`
+fn write_unified_diff(output: &mut dyn Write, path: &str, original: &str, formatted: &str) -> io::Result<()> {
+    const CONTEXT: usize = 3;
+    let original: Vec<&str> = original.split_inclusive('\n').collect();
+    let formatted: Vec<&str> = formatted.split_inclusive('\n').collect();
+    let ops = diff_lines(&original, &formatted);
+    writeln!(output, "--- {}", path)?;
+    writeln!(output, "+++ {}", path)?;
+
+    // group changes with their context into hunks, merging hunks whose context would overlap
+    let changes: Vec<usize> = ops.iter().positions(|op| *op != DiffOp::Equal).collect();
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for index in changes {
+        let (start, end) = (index.saturating_sub(CONTEXT), (index + CONTEXT + 1).min(ops.len()));
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let (mut i, mut j, mut position) = (0, 0, 0);
+    for (start, end) in hunks {
+        for op in &ops[position..start] {
+            i += usize::from(*op != DiffOp::Insert);
+            j += usize::from(*op != DiffOp::Delete);
+        }
+        let hunk_ops = &ops[start..end];
+        let original_len = hunk_ops.iter().filter(|op| **op != DiffOp::Insert).count();
+        let formatted_len = hunk_ops.iter().filter(|op| **op != DiffOp::Delete).count();
+        writeln!(output, "@@ -{} +{} @@", hunk_range(i, original_len), hunk_range(j, formatted_len))?;
+        for op in hunk_ops {
+            match op {
+                DiffOp::Equal => {
+                    write_diff_line(output, ' ', original[i])?;
+                    i += 1;
+                    j += 1;
+                }
+                DiffOp::Delete => {
+                    write_diff_line(output, '-', original[i])?;
+                    i += 1;
+                }
+                DiffOp::Insert => {
+                    write_diff_line(output, '+', formatted[j])?;
+                    j += 1;
+                }
+            }
+        }
+        position = end;
+    }
+    Ok(())
+}
+
+fn check_formatting(path: &str, input: Option<&mut dyn BufRead>, output: &mut dyn Write, args: &Config, substitution_rules: &[SubstitutionRule], stats: &mut Stats) -> Result<(), FormatError> {
+    let FormattedBuffer { original, formatted, .. } = match input {
+        Some(input) => format_to_buffer("-", input, args, substitution_rules, stats)?,
+        None => format_file_to_buffer(path, args, substitution_rules, stats)?,
+    };
+    if original == formatted {
+        return Ok(());
+    }
+    if args.diff {
+        write_unified_diff(output, path, &String::from_utf8_lossy(&original), &String::from_utf8_lossy(&formatted))?;
+    }
+    Err(FormatError::NotFormatted { path: path.to_owned() })
+}
+
+fn compile_substitution_pattern(regex: &str, flags: &str) -> Result<SubstitutionPattern, ConfigError> {
+    let mut builder = RegexBuilder::new(regex);
+    let mut literal = false;
+    for flag in flags.chars() {
+        match flag {
+            'i' => builder.case_insensitive(true),
+            'm' => builder.multi_line(true),
+            's' => builder.dot_matches_new_line(true),
+            'l' => {
+                literal = true;
+                &mut builder
+            }
+            _ => return Err(ConfigError::RegexFlags { flags: flags.to_owned(), flag }),
+        };
+    }
+    if literal {
+        return Ok(SubstitutionPattern::Literal(regex.to_owned()));
+    }
+    builder.build().map(SubstitutionPattern::Regex).map_err(|error| ConfigError::Regex { regex: regex.to_owned(), error })
+}
+
+/// Reads the tab-separated regex/replacement pairs from the specified file and appends them to the specified rules
+fn read_substitution_file(path: &str, config: &Config, substitution_rules: &mut Vec<SubstitutionRule>, warnings: &mut Vec<ConfigError>) -> Result<(), ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|error| ConfigError::SubstitutionFile { path: path.to_owned(), error })?;
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let malformed = |reason: String| ConfigError::SubstitutionFileLine { path: path.to_owned(), line_number: index + 1, reason };
+        let Some((regex, replacement)) = line.split_once('\t') else {
+            return Err(malformed("expected a regex and a replacement separated by a tab".to_owned()));
+        };
+        let pattern = match compile_substitution_pattern(regex, "") {
+            Ok(pattern) => pattern,
+            Err(ConfigError::Regex { error, .. }) if config.skip_invalid_rules => {
+                warnings.push(ConfigError::SkippedRule { error: Box::new(malformed(error.to_string())) });
+                continue;
+            }
+            Err(ConfigError::Regex { error, .. }) => return Err(malformed(error.to_string())),
+            Err(error) => return Err(error),
+        };
+        substitution_rules.push(SubstitutionRule { pattern, replacement: replacement.to_owned(), count: None });
+    }
+    Ok(())
+}
+
+/// Returns the first capture group referenced by `replacement` (via `$N`, `$name` or `${name}`) that `regex` does not define
+fn unknown_capture_group_reference(regex: &Regex, replacement: &str) -> Option<String> {
+    let mut rest = replacement;
+    while let Some(dollar_index) = rest.find('$') {
+        rest = &rest[dollar_index + 1..];
+        let reference = if let Some(escaped) = rest.strip_prefix('$') {
+            rest = escaped;
+            continue;
+        } else if let Some(braced) = rest.strip_prefix('{') {
+            let Some(end) = braced.find('}') else { continue };
+            rest = &braced[end + 1..];
+            &braced[..end]
+        } else {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+        let is_defined = match reference.parse::<usize>() {
+            Ok(index) => index < regex.captures_len(),
+            Err(_) => reference.is_empty() || regex.capture_names().flatten().any(|name| name == reference),
+        };
+        if !is_defined {
+            return Some(reference.to_owned());
+        }
+    }
+    None
+}
+
+/// Returns an error if the capture groups referenced by a replacement are not defined by the regex of its rule
+fn validate_capture_group_references(substitution_rules: &[SubstitutionRule]) -> Result<(), ConfigError> {
+    for rule in substitution_rules {
+        let SubstitutionPattern::Regex(regex) = &rule.pattern else { continue };
+        if let Some(reference) = unknown_capture_group_reference(regex, &rule.replacement) {
+            return Err(ConfigError::ReplacementReference { regex: regex.as_str().to_owned(), replacement: rule.replacement.clone(), reference });
+        }
+    }
+    Ok(())
+}
+
+/// Returns an error if options contradict each other, adding problems that do not prevent formatting to `warnings`
+///
+/// This also checks the conflicts clap already rejects when parsing the command-line for configs constructed directly.
+fn validate_config(config: &Config, warnings: &mut Vec<ConfigError>) -> Result<(), ConfigError> {
+    let log = config.substitution_log.is_some();
+    let json = config.output_format == OutputFormat::Json;
+    let conflicts = [
+        (config.truncate && config.rewrap, "truncate", "rewrap"),
+        (config.truncate && config.max_words > 0, "max-words", "truncate"),
+        (config.indent_string.is_some() && config.indent > 0, "indent-string", "indent"),
+        (config.number_lines && config.number_nonblank, "number-lines", "number-nonblank"),
+        (log && config.substitute_multiline, "substitution-log", "substitute-multiline"),
+        (log && config.in_place, "substitution-log", "in-place"),
+        (log && config.output_dir.is_some(), "substitution-log", "output-dir"),
+        (log && config.check, "substitution-log", "check"),
+        (log && config.diff, "substitution-log", "diff"),
+        (config.drop_emptied_lines && config.substitute_multiline, "drop-emptied-lines", "substitute-multiline"),
+        (config.skip_invalid_rules && config.strict, "skip-invalid-rules", "strict"),
+        (config.output_dir.is_some() && config.in_place, "output-dir", "in-place"),
+        (config.output_dir.is_some() && config.check, "output-dir", "check"),
+        (config.output_dir.is_some() && config.diff, "output-dir", "diff"),
+        (config.output_dir.is_some() && json, "output-dir", "format"),
+        (config.check && config.in_place, "check", "in-place"),
+        (config.diff && config.in_place, "diff", "in-place"),
+        (config.quiet && config.verbose, "quiet", "verbose"),
+        (config.quiet && config.warn_long_words, "quiet", "warn-long-words"),
+        (json && config.in_place, "format", "in-place"),
+        (json && config.check, "format", "check"),
+        (json && config.diff, "format", "diff"),
+    ];
+    if let Some((_, option, other_option)) = conflicts.into_iter().find(|(conflicts, _, _)| *conflicts) {
+        return Err(ConfigError::ConflictingOptions { option, other_option });
+    }
+    if config.align != Alignment::Left && config.max_line_length == 0 {
+        warnings.push(ConfigError::AlignWithoutMaxLineLength);
+    }
+    if config.max_line_length > 0 && config.right_margin >= config.max_line_length {
+        return Err(ConfigError::RightMargin { right_margin: config.right_margin, max_line_length: config.max_line_length });
+    }
+    if let Some(output_dir) = &config.output_dir {
+        let mut output_paths = std::collections::HashMap::new();
+        for path in config.input_files.iter().filter(|path| *path != "-") {
+            let output_path = output_dir_path(path, output_dir);
+            if let Some(other_path) = output_paths.insert(output_path.clone(), path) {
+                return Err(ConfigError::OutputFileCollision { path: path.clone(), other_path: other_path.clone(), output_path: output_path.to_string_lossy().into_owned() });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses the regexes for substitution including the ones from the --substitute-file, adding problems that do not
+/// prevent formatting (like rules skipped via --skip-invalid-rules) to `warnings`
+fn compile_substitution_rules(config: &Config, warnings: &mut Vec<ConfigError>) -> Result<Vec<SubstitutionRule>, ConfigError> {
+    if config.replacement.len() > config.substitute_regex.len() {
+        let error = ConfigError::ReplacementCount { regexes: config.substitute_regex.len(), replacements: config.replacement.len() };
+        if config.strict {
+            return Err(error);
+        }
+        warnings.push(error);
+    }
+
+    let mut substitution_rules = Vec::new();
+    for (index, regex) in config.substitute_regex.iter().enumerate() {
+        let flags = config.substitute_flags.get(index).map_or("", String::as_str);
+        let pattern = match compile_substitution_pattern(regex, flags) {
+            Err(error @ ConfigError::Regex { .. }) if config.skip_invalid_rules => {
+                warnings.push(ConfigError::SkippedRule { error: Box::new(error) });
+                continue;
+            }
+            result => result?,
+        };
+        substitution_rules.push(SubstitutionRule {
+            pattern,
+            replacement: config.replacement.get(index).cloned().unwrap_or_default(),
+            count: config.substitute_count.get(index).copied(),
+        });
+    }
+    if let Some(path) = &config.substitute_file {
+        read_substitution_file(path, config, &mut substitution_rules, warnings)?;
+    }
+    if config.strict {
+        validate_capture_group_references(&substitution_rules)?;
+    }
+    Ok(substitution_rules)
+}
+
+/// Prints the specified error or warning to stderr unless `config.quiet` is set
+fn report(config: &Config, message: &dyn fmt::Display) {
+    if !config.quiet {
+        eprintln!("{}", message);
+    }
+}
+
+/// Prints the specified input file to stderr when `config.verbose` is set
+fn report_progress(config: &Config, path: &str) {
+    if config.verbose {
+        eprintln!("Formatting \"{}\"", path);
+    }
+}
+
+/// Formats the input files independently of each other using `config.jobs` threads
+///
+/// The formatted files are written to `output` in the order they were specified (unless formatting
+/// in-place). An input file of "-" is formatted on the calling thread as `input` cannot be shared.
+fn format_files_concurrently(config: &Config, substitution_rules: &[SubstitutionRule], input: &mut dyn BufRead, output: &mut dyn Write, stats: &mut Stats) -> Result<(), FormatError> {
+    let jobs = match config.jobs {
+        0 => thread::available_parallelism().map_or(1, usize::from),
+        jobs => jobs,
+    };
+    let next_index = AtomicUsize::new(0);
+    let mut file_results: Vec<Option<Result<FormattedBuffer, FormatError>>> = config.input_files.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.min(config.input_files.len())).map(|_| scope.spawn(|| {
+            let mut worker_results = Vec::new();
+            let mut worker_stats = Stats::default();
+            loop {
+                let index = next_index.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = config.input_files.get(index) else { break };
+                if path == "-" {
+                    continue;
+                }
+                report_progress(config, path);
+                let file_result = if writes_input_files(config) {
+                    format_file_in_place(path, config, substitution_rules, &mut worker_stats).map(|()| FormattedBuffer::default())
+                } else {
+                    format_file_to_buffer(path, config, substitution_rules, &mut worker_stats).map(|buffer| FormattedBuffer { original: Vec::new(), ..buffer })
+                };
+                worker_results.push((index, file_result));
+            }
+            (worker_results, worker_stats)
+        })).collect();
+        for worker in workers {
+            let (worker_results, worker_stats) = worker.join().expect("formatting thread panicked");
+            for (index, file_result) in worker_results {
+                file_results[index] = Some(file_result);
+            }
+            *stats += worker_stats;
+        }
+    });
+
+    // write the line ending omitted at the end of a buffer when more output follows like the sequential path does
+    let mut result = Ok(());
+    let mut pending_line_ending: Option<&str> = None;
+    for file_result in file_results {
+        let FormattedBuffer { formatted, omitted_line_ending, .. } = match file_result {
+            Some(Ok(buffer)) => buffer,
+            Some(Err(error)) if error.concerns_single_file() => {
+                report(config, &error);
+                result = Err(error);
+                continue;
+            }
+            Some(Err(error)) => return Err(error),
+            None => {
+                report_progress(config, "-");
+                format_to_buffer("-", &mut *input, config, substitution_rules, stats)?
+            }
+        };
+        if formatted.is_empty() {
+            continue;
+        }
+        if let Some(line_ending) = pending_line_ending.take() {
+            output.write_all(line_ending.as_bytes())?;
+        }
+        output.write_all(&formatted)?;
+        pending_line_ending = omitted_line_ending;
+    }
+    result
+}
+
+/// Formats the input according to the specified `config`, writing the result to `output`
+///
+/// Reads from `input` unless `config.input_files` is non-empty; an input file of "-" refers to
+/// `input` as well. With `config.in_place` each input file (except "-") is written back instead (or into
+/// `config.output_dir` if specified). An input file that cannot be opened
+/// (or read or written back in-place) is reported on stderr right away and skipped so the
+/// remaining files are still processed; the error about the last such file is returned in the
+/// end. Other errors abort the formatting immediately.
+///
+/// The input files are treated as one continuous document, so when rewrapping a paragraph may span
+/// multiple files unless `config.separate` is set.
+///
+/// With `config.check` nothing is written to `output`; instead each input that would be changed by
+/// formatting is reported like an input file that cannot be opened. With `config.diff` a unified diff
+/// of such inputs is written to `output` instead.
+pub fn format(config: &Config, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), FormatError> {
+    format_and_count(config, input, output, &mut Stats::default())
+}
+
+/// Like `format` but returns the number of lines, wraps, substitutions and bytes processed
+pub fn format_with_stats(config: &Config, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<Stats, FormatError> {
+    let mut stats = Stats::default();
+    format_and_count(config, input, output, &mut stats)?;
+    Ok(stats)
+}
+
+/// Like `format` but adds the counters to `stats` (also the ones of inputs processed before an error occurred)
+fn format_and_count(config: &Config, input: &mut dyn BufRead, output: &mut dyn Write, stats: &mut Stats) -> Result<(), FormatError> {
+    let mut warnings = Vec::new();
+    let substitution_rules = validate_config(config, &mut warnings).and_then(|()| compile_substitution_rules(config, &mut warnings))?;
+    for warning in &warnings {
+        report(config, warning);
+    }
+
+    // read input line-by-line and echo a formatted version of the input
+    let mut output = OutputState::new(output, config);
+    if let Some(path) = &config.substitution_log {
+        output.substitution_log = Some(SubstitutionLog::create(path)?);
+    }
+    let mut result = Ok(());
+    let mut paragraph = ParagraphState::default();
+    if config.check || config.diff {
+        let stdin = [String::from("-")];
+        let input_files = if config.input_files.is_empty() { &stdin[..] } else { &config.input_files[..] };
+        for input_file_path in input_files {
+            report_progress(config, input_file_path);
+            let check_result = if input_file_path == "-" {
+                check_formatting("<stdin>", Some(&mut *input), output.writer.inner, config, &substitution_rules, stats)
+            } else {
+                check_formatting(input_file_path, None, output.writer.inner, config, &substitution_rules, stats)
+            };
+            match check_result {
+                Err(error) if error.concerns_single_file() => {
+                    // the diff already shows that the input is not formatted
+                    if !config.diff || !matches!(error, FormatError::NotFormatted { .. }) {
+                        report(config, &error);
+                    }
+                    result = Err(error);
+                }
+                check_result => check_result?,
+            }
+        }
+        return result;
+    } else if config.jobs != 1 && config.input_files.len() > 1 && config.output_format == OutputFormat::Text && config.substitution_log.is_none() {
+        return format_files_concurrently(config, &substitution_rules, input, output.writer.inner, stats);
+    } else if config.input_files.is_empty() {
+        read_lines(&mut output, input, &mut paragraph, config, &substitution_rules)?;
+    } else {
+        for input_file_path in &config.input_files {
+            // end the paragraph of the previous file unless files are concatenated
+            if config.separate && !config.concatenate {
+                flush_paragraph(&mut output, &mut paragraph, config)?;
+                paragraph = ParagraphState::default();
+            }
+            report_progress(config, input_file_path);
+            output.start_input(input_file_path);
+            if input_file_path == "-" {
+                read_lines(&mut output, &mut *input, &mut paragraph, config, &substitution_rules)?;
+                continue;
+            }
+            if writes_input_files(config) {
+                if let Err(error) = format_file_in_place(input_file_path, config, &substitution_rules, stats) {
+                    report(config, &error);
+                    result = Err(error);
+                }
+                continue;
+            }
+            let mut input_file_reader = match File::open(input_file_path) {
+                Ok(input_file) => BufReader::new(input_file),
+                Err(error) => {
+                    let error = FormatError::FileOpen { path: input_file_path.clone(), error };
+                    report(config, &error);
+                    result = Err(error);
+                    continue;
+                }
+            };
+            read_lines(&mut output, &mut input_file_reader, &mut paragraph, config, &substitution_rules)?;
+        }
+    }
+
+    finish_output(&mut output, &mut paragraph, config)?;
+    *stats += output.stats();
+    if let Some(log) = output.substitution_log.take() {
+        log.finish()?;
+    }
+
+    result
+}
+
+/// Formats input which is passed line-by-line, e.g. when reading the input from a socket
+///
+/// Each input line is passed via `push_line` without its terminator, returning the output lines that
+/// are complete so far (also without terminator). When rewrapping, the last output line is kept back
+/// as the paragraph might be continued by the next input line, so `finish` must be called after the
+/// last input line to get it. Substitutions are always applied per line (`substitute_multiline`, `substitution_log`,
+/// `output_format` and input files are ignored).
+pub struct Formatter {
+    config: Config,
+    substitution_rules: Vec<SubstitutionRule>,
+    output: OutputState<Vec<u8>>,
+    paragraph: ParagraphState,
+    scratch: String,
+}
+
+impl Formatter {
+    /// Creates a new formatter for the specified `config`, failing if a substitution rule cannot be parsed
+    pub fn new(mut config: Config) -> Result<Self, FormatError> {
+        config.output_format = OutputFormat::Text;
+        config.substitution_log = None;
+        let mut warnings = Vec::new();
+        validate_config(&config, &mut warnings)?;
+        let substitution_rules = compile_substitution_rules(&config, &mut warnings)?;
+        for warning in &warnings {
+            report(&config, warning);
+        }
+        Ok(Formatter {
+            substitution_rules,
+            output: OutputState::new(Vec::new(), &config),
+            paragraph: ParagraphState::default(),
+            scratch: String::new(),
+            config,
+        })
+    }
+
+    /// Formats the specified input line returning the output lines that are complete so far
+    pub fn push_line(&mut self, line: &str) -> Vec<String> {
+        let mut line = line.to_owned();
+        let offset = self.output.input_byte_range.end;
+        self.output.input_byte_range = offset..offset + line.len() + 1;
+        process_line(&mut self.output, &mut line, &mut self.paragraph, &self.config, &self.substitution_rules, &mut self.scratch)
+            .expect("writing to a Vec must not fail");
+        self.output.report_warnings(&self.config);
+        self.take_output_lines()
+    }
+
+    /// Returns the output lines still kept back; the formatter can be used for further input afterwards
+    pub fn finish(&mut self) -> Vec<String> {
+        flush_paragraph(&mut self.output, &mut self.paragraph, &self.config).expect("writing to a Vec must not fail");
+        self.take_output_lines()
+    }
+
+    fn take_output_lines(&mut self) -> Vec<String> {
+        // a pending line ending means at least one line (which might be empty) has been written
+        if self.output.pending_line_ending.take().is_none() {
+            return Vec::new();
+        }
+        let output = String::from_utf8(std::mem::take(&mut self.output.writer.inner)).expect("output must be valid UTF-8");
+        output.split(self.output.line_ending).map(str::to_owned).collect()
+    }
+}
+
+/// Formats the specified `input` according to the specified `config`, returning the result
+///
+/// Every output line is terminated with a newline character. The last one is only terminated if
+/// `input` ends with a newline character as well (or is empty). The input files of `config` are
+/// supposed to be empty; otherwise they are read instead of `input`.
+pub fn format_string(config: &Config, input: &str) -> Result<String, FormatError> {
+    let mut output = Vec::new();
+    format(config, &mut Cursor::new(input), &mut output)?;
+    Ok(String::from_utf8(output).unwrap())
+}
+
+/// Breaks the specified `line` (without terminator) according to `config`, returning the resulting lines (without
+/// terminator)
+///
+/// The line is always wrapped, so options that select, filter or combine input lines are ignored: substitutions,
+/// rewrapping, input files, `lines`, `byte_range`, `verbatim_delimiter`, `wrap_if`, `no_wrap_if`, `detect_tables`,
+/// `truncate`, the blank line options (an empty line results in a single empty line) and `ensure_final_newline`.
+/// Options like `indent` and `number_lines` still apply to each resulting line. When preserving list indentation the
+/// lines resulting from breaking a list item start with spaces up to its text, e.g. "- foo bar" is broken into
+/// "- foo" and "  bar" with a maximum line length of 5.
+///
+/// Fails if the remaining options contradict each other or if `line` contains the line terminator ("\n" or "\0"
+/// when using `null`).
+pub fn wrap_line(line: &str, config: &Config) -> Result<Vec<String>, FormatError> {
+    let terminator = if config.null { '\0' } else { '\n' };
+    if line.contains(terminator) {
+        return Err(FormatError::Io(io::Error::new(io::ErrorKind::InvalidInput, "the line to wrap must not contain the line terminator")));
+    }
+    let config = Config {
+        substitute_regex: Vec::new(),
+        replacement: Vec::new(),
+        substitute_flags: Vec::new(),
+        substitute_count: Vec::new(),
+        substitute_file: None,
+        substitute_multiline: false,
+        drop_emptied_lines: false,
+        rewrap: false,
+        input_files: Vec::new(),
+        lines: None,
+        byte_range: None,
+        verbatim_delimiter: None,
+        wrap_if: None,
+        no_wrap_if: None,
+        detect_tables: false,
+        truncate: false,
+        squeeze_blank: false,
+        max_blank_lines: None,
+        remove_blank_lines: false,
+        ensure_final_newline: false,
+        ..config.clone()
+    };
+    let mut formatter = Formatter::new(config)?;
+    let mut lines = formatter.push_line(line);
+    lines.extend(formatter.finish());
+    Ok(lines)
+}
+
+fn format_to_file(path: &str, config: &Config, input: &mut dyn BufRead, stats: &mut Stats) -> Result<(), FormatError> {
+    let to_output_file_error = |error| FormatError::OutputFile { path: path.to_owned(), error };
+    let mut output_file = BufWriter::new(File::create(path).map_err(to_output_file_error)?);
+    let result = format_and_count(config, input, &mut output_file, stats);
+    output_file.flush().map_err(to_output_file_error)?;
+    result
+}
+
+fn run_cli(cli: &Cli, output: &mut dyn Write, input: &mut dyn BufRead) -> i32 {
+    let mut stats = Stats::default();
+    let result = match &cli.output {
+        Some(path) => format_to_file(path, &cli.config, input, &mut stats),
+        None => format_and_count(&cli.config, input, output, &mut stats).and_then(|()| Ok(output.flush()?)),
+    };
+    if cli.config.stats {
+        eprint!("{}", stats);
+    }
+    match result {
+        Ok(()) => 0,
+        // errors about input files have already been reported while processing the remaining files
+        Err(error) if error.concerns_single_file() => 1,
+        Err(error) => {
+            report(&cli.config, &error);
+            1
+        }
+    }
+}
+
+/// Merges the --substitute-literal rules into the --substitute-regex rules in the order they were specified
+fn merge_literal_substitutions(cli: &mut Cli, matches: &clap::ArgMatches) {
+    if cli.substitute_literal.is_empty() {
+        return;
+    }
+    let regex_indices = matches.indices_of("substitute_regex").into_iter().flatten();
+    let literal_indices = matches.indices_of("substitute_literal").into_iter().flatten();
+    let regex_rules = regex_indices.zip(std::mem::take(&mut cli.config.substitute_regex)).map(|(index, rule)| (index, rule, false));
+    let literal_rules = literal_indices.zip(std::mem::take(&mut cli.substitute_literal)).map(|(index, rule)| (index, rule, true));
+    let config = &mut cli.config;
+    for (position, (_, rule, literal)) in regex_rules.chain(literal_rules).sorted_by_key(|(index, _, _)| *index).enumerate() {
+        config.substitute_regex.push(rule);
+        if literal {
+            if config.substitute_flags.len() <= position {
+                config.substitute_flags.resize(position + 1, String::new());
+            }
+            config.substitute_flags[position].push('l');
+        }
+    }
+}
+
+/// Sets the max line length to the terminal width (or a fallback) if --auto-width is used without --max-line-length
+///
+/// The `configured_options` are the ones set via the configuration file or environment variables; clap only sees them
+/// as defaults.
+fn resolve_auto_width(cli: &mut Cli, matches: &clap::ArgMatches, configured_options: &[String], terminal_width: Option<usize>) {
+    let is_explicit = matches.value_source("max_line_length") == Some(clap::parser::ValueSource::CommandLine)
+        || configured_options.iter().any(|option| option == "max_line_length");
+    if cli.auto_width && !is_explicit {
+        cli.config.max_line_length = terminal_width.unwrap_or(80);
+    }
+}
+
+/// Returns the value of the --config option by scanning the raw command-line arguments
+fn config_file_arg(args: &[std::ffi::OsString]) -> Option<String> {
+    let mut args = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            return args.next().map(Cow::into_owned);
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_owned());
+        }
+    }
+    None
+}
+
+fn toml_value_to_string(value: toml::Value) -> String {
+    match value {
+        toml::Value::String(value) => value,
+        value => value.to_string(),
+    }
+}
+
+/// Makes the values from the specified configuration file (or .formatterrc if present) the defaults of the arguments,
+/// adding the options they are set for to `configured_options`
+fn apply_config_file(mut command: clap::Command, path: Option<&str>, configured_options: &mut Vec<String>) -> Result<clap::Command, String> {
+    let (path, is_explicit) = match path {
+        Some(path) => (path, true),
+        None => (".formatterrc", false),
+    };
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if !is_explicit && error.kind() == io::ErrorKind::NotFound => return Ok(command),
+        Err(error) => return Err(format!("Unable to read \"{}\": {}", path, error)),
+    };
+    let table: toml::Table = contents.parse().map_err(|error| format!("Unable to parse \"{}\": {}", path, error))?;
+    for (key, value) in table {
+        if !command.get_arguments().any(|arg| arg.get_id() == key.as_str()) {
+            return Err(format!("Unable to parse \"{}\": unknown option \"{}\"", path, key));
+        }
+        let values: Vec<String> = match value {
+            toml::Value::Array(values) => values.into_iter().map(toml_value_to_string).collect(),
+            value => vec![toml_value_to_string(value)],
+        };
+        command = command.mut_arg(&key, |arg| arg.default_values(values));
+        configured_options.push(key);
+    }
+    Ok(command)
+}
+
+/// Makes the values of the environment variables for the options (e.g. FORMATTER_MAX_LINE_LENGTH) the defaults of the
+/// arguments, overriding the ones from the configuration file; `env` looks up the variables and the options they are
+/// set for are added to `configured_options`
+fn apply_env_vars(command: clap::Command, env: &impl Fn(&str) -> Option<String>, configured_options: &mut Vec<String>) -> clap::Command {
+    command.mut_args(|arg| {
+        if arg.is_positional() {
+            return arg;
+        }
+        let Some(value) = env(&format!("FORMATTER_{}", arg.get_id().as_str().to_uppercase())) else { return arg };
+        configured_options.push(arg.get_id().to_string());
+        if matches!(arg.get_action(), clap::ArgAction::SetTrue) {
+            // treat flags like clap does when reading them from the environment
+            let is_falsey = ["", "0", "n", "no", "f", "false", "off"].contains(&value.to_lowercase().as_str());
+            return arg.default_value(if is_falsey { "false" } else { "true" });
+        }
+        arg.default_value(value)
+    })
+}
+
+fn parse_cli<I, T>(args: I) -> Cli
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    parse_cli_with_env(args, |name| std::env::var(name).ok())
+}
+
+/// Parses the specified arguments looking up environment variables via `env`
+fn parse_cli_with_env<I, T>(args: I, env: impl Fn(&str) -> Option<String>) -> Cli
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let args: Vec<std::ffi::OsString> = args.into_iter().map(Into::into).collect();
+    let config_file = config_file_arg(&args).or_else(|| env("FORMATTER_CONFIG_FILE"));
+    let mut configured_options = Vec::new();
+    let command = apply_config_file(Cli::command(), config_file.as_deref(), &mut configured_options)
+        .map(|command| apply_env_vars(command, &env, &mut configured_options))
+        .unwrap_or_else(|message| Cli::command().error(clap::error::ErrorKind::Io, message).exit());
+    let matches = command.get_matches_from(args);
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    merge_literal_substitutions(&mut cli, &matches);
+    resolve_auto_width(&mut cli, &matches, &configured_options, terminal_size::terminal_size().map(|(terminal_size::Width(width), _)| usize::from(width)));
+    cli
+}
+
+/// Parses the command-line arguments and formats the input accordingly
+pub fn run(output: &mut dyn Write, input: &mut dyn BufRead) -> i32 {
+    run_cli(&parse_cli(std::env::args_os()), output, input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::path::Path;
+
+    fn test_read_lines(expected: &[u8], input_data: &[u8], args: &Config) {
+        let mut input = Cursor::new(Vec::new());
+        let mut output = Cursor::new(Vec::new());
+
+        // write some test data
+        input.write_all(input_data).unwrap();
+        input.seek(SeekFrom::Start(0)).unwrap();
+
+        // read the test data
+        format(args, &mut input, &mut output).unwrap();
+
+        // check the output
+        let mut result = Vec::new();
+        output.seek(SeekFrom::Start(0)).unwrap();
+        output.read_to_end(&mut result).unwrap();
+        assert_eq!(String::from_utf8(expected.to_vec()).unwrap(), String::from_utf8(result).unwrap());
+    }
+
+    #[test]
+    fn test_simple_one_liner() {
+        let mk_args = ||
+            Config{ break_words: true, keep_trailing_whitespaces: true, ..Config::default() };
+        test_read_lines(b"foo\n", b"foo\n", &mk_args());
+    }
+
+    #[test]
+    fn test_line_wrapping_with_word_breaks() {
+        let mk_args = |max_line_length_: usize, keep_trailing_whitespaces_: bool|
+            Config{ max_line_length: max_line_length_, break_words: true, keep_trailing_whitespaces: keep_trailing_whitespaces_, ..Config::default() };
+        test_read_lines(b"foo bar ba\nz\n", b"foo bar baz\n", &mk_args(10, false));
+        test_read_lines(b"foo bar ba\nz\n", b"foo bar baz\n", &mk_args(10, true));
+        test_read_lines(b"fo\no\nba\nr\nba\nz\n", b"foo bar baz\n", &mk_args(2, false));
+        test_read_lines(b"fo\no \nba\nr \nba\nz\n", b"foo bar baz\n", &mk_args(2, true));
+        test_read_lines(b"fooba\nr\nbaz\n", b"foobar\nbaz\n", &mk_args(5, false));
+    }
+
+    #[test]
+    fn test_line_wrapping_without_work_breaks() {
+        let mk_args = |max_line_length_: usize, keep_trailing_whitespaces_: bool|
+            Config{ max_line_length: max_line_length_, keep_trailing_whitespaces: keep_trailing_whitespaces_, ..Config::default() };
+        test_read_lines(b"foo bar\nbaz\n", b"foo bar baz\n", &mk_args(10, false));
+        test_read_lines(b"foo bar \nbaz\n", b"foo bar baz\n", &mk_args(10, true));
+        test_read_lines(b"foo\nbar\nbaz\n", b"foo bar baz\n", &mk_args(2, false));
+        test_read_lines(b"foobar\nbaz\nt1 t2\n", b"foobar\nbaz t1 t2\n", &mk_args(5, false));
+    }
+
+    #[test]
+    fn test_line_wrapping_without_leading_whitespaces() {
+        let mk_args = |max_line_length_: usize, break_words_: bool, rewrap_: bool| Config{
+            max_line_length: max_line_length_, break_words: break_words_, rewrap: rewrap_, keep_trailing_whitespaces: true,
+            preserve_list_indentation: true, ..Config::default() };
+        test_read_lines(b"foo\nbar\nbaz\n", b"foo   bar baz\n", &mk_args(3, false, false));
+        test_read_lines(b"foo\nbar\nbaz\n", b"foo   bar baz\n", &mk_args(3, true, false));
+        test_read_lines(b"- foo\n  bar\n", b"- foo   bar\n", &mk_args(5, false, false));
+        test_read_lines(b"- foo\n  bar\n", b"- foo \nbar\n", &mk_args(5, false, true));
+        let mut args = mk_args(0, false, false);
+        args.trim_trailing = true;
+        test_read_lines(b"foo\n", b"foo  \n", &args);
+    }
+
+    #[test]
+    fn test_line_wrapping_at_limit() {
+        let mk_args = |max_line_length_: usize, break_words_: bool|
+            Config{ max_line_length: max_line_length_, break_words: break_words_, ..Config::default() };
+        // a word exactly filling the line, one char under and one char over the limit
+        test_read_lines(b"foo bar\nbaz\n", b"foo bar baz\n", &mk_args(7, false));
+        test_read_lines(b"foo\nbar\nbaz\n", b"foo bar baz\n", &mk_args(6, false));
+        test_read_lines(b"foo bar\nbaz\n", b"foo bar baz\n", &mk_args(8, false));
+        test_read_lines(b"foo bar\nbaz\n", b"foo bar baz\n", &mk_args(7, true));
+        test_read_lines(b"foo ba\nr baz\n", b"foo bar baz\n", &mk_args(6, true));
+        test_read_lines(b"foobar\n", b"foobar\n", &mk_args(6, false));
+        test_read_lines(b"foobar\n", b"foobar\n", &mk_args(5, false));
+
+        // no line exceeds the limit except an unbreakable word
+        let input = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore\n";
+        for max_line_length in 1..=input.len() {
+            for break_words in [false, true] {
+                let output = format_string(&mk_args(max_line_length, break_words), input).unwrap();
+                for line in output.lines() {
+                    assert!(line.len() <= max_line_length || (!break_words && !line.contains(' ')), "{:?} exceeds {}", line, max_line_length);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_line_wrapping_with_graphemes() {
+        let mk_args = |max_line_length_: usize, width_mode_: WidthMode| Config{ max_line_length: max_line_length_, width_mode: width_mode_, break_words: true, ..Config::default() };
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        let flag = "\u{1f1e9}\u{1f1ea}";
+        let input = format!("{}{} a{}\n", family, flag, family);
+        test_read_lines(format!("{}{}\na{}\n", family, flag, family).as_bytes(), input.as_bytes(), &mk_args(4, WidthMode::Grapheme));
+        test_read_lines(format!("{}\n{}\n", family, flag).as_bytes(), format!("{}{}\n", family, flag).as_bytes(), &mk_args(3, WidthMode::Grapheme));
+        test_read_lines("\u{1f468}\u{200d}\n\u{1f469}\u{200d}\n\u{1f467}\u{1f1e9}\n\u{1f1ea}\n".as_bytes(), format!("{}{}\n", family, flag).as_bytes(), &mk_args(2, WidthMode::Chars));
+    }
+
+    #[test]
+    fn test_line_wrapping_with_urls() {
+        let mk_args = |break_words_: bool, break_on_hyphens_: bool| Config{ max_line_length: 10, break_words: break_words_, break_on_hyphens: break_on_hyphens_, ..Config::default() };
+        let input = b"see https://example.com/foo-bar and\nhttp://example.com\n";
+        let expected = b"see\nhttps://example.com/foo-bar\nand\nhttp://example.com\n";
+        test_read_lines(expected, input, &mk_args(false, false));
+        test_read_lines(expected, input, &mk_args(true, false));
+        test_read_lines(expected, input, &mk_args(true, true));
+        test_read_lines(b"seehttps:/\n/example.c\nom\n", b"seehttps://example.com\n", &mk_args(true, false));
+    }
+
+    #[test]
+    fn test_line_wrapping_on_hyphens() {
+        let mk_args = |break_on_hyphens_: bool| Config{ max_line_length: 12, break_on_hyphens: break_on_hyphens_, preserve_list_indentation: true, ..Config::default() };
+        let input = b"The state-of-the-art-configuration\n";
+        test_read_lines(b"The\nstate-of-the-art-configuration\n", input, &mk_args(false));
+        test_read_lines(b"The state-\nof-the-art-\nconfiguratio\n", b"The state-of-the-art-configuratio\n", &mk_args(true));
+        test_read_lines(b"- foo and/or\n  -bar\n", b"- foo and/or -bar\n", &mk_args(true));
+        test_read_lines(b"- foo-bar-\n  baz\n", b"- foo-bar-baz\n", &mk_args(true));
+    }
+
+    #[test]
+    fn test_wrap_after() {
+        let mk_args = |wrap_after_: &str| Config{ max_line_length: 20, wrap_after: wrap_after_.to_owned(), ..Config::default() };
+        let input = b"foo, bar baz, qux quux; corge\n";
+        test_read_lines(b"foo, bar baz,\nqux quux; corge\n", input, &mk_args(",;"));
+        test_read_lines(b"foo, bar baz, qux\nquux; corge\n", input, &mk_args(""));
+        test_read_lines(b"a long list,\nof items\n", b"a long list, of items\n", &Config{ max_line_length: 16, ..mk_args(",") });
+        test_read_lines(b"a,\nb c d e f g h i\n", b"a, b c d e f g h i\n", &Config{ max_line_length: 16, ..mk_args(",") });
+        test_read_lines(b"a,\nb c d e f\ng h i\n", b"a, b c d e f g h i\n", &Config{ max_line_length: 10, ..mk_args(",") });
+    }
+
+    #[test]
+    fn test_sentence_spacing() {
+        let mk_args = |sentence_spacing_: usize| Config{ rewrap: true, sentence_spacing: sentence_spacing_, ..Config::default() };
+        let input = b"This ends.\nThis does\nnot (really!)\nend\n";
+        test_read_lines(b"This ends.  This does not (really!)  end\n", input, &mk_args(2));
+        test_read_lines(b"This ends. This does not (really!) end\n", input, &mk_args(1));
+        test_read_lines(b"This ends.\nThis does\n", b"This ends.\nThis does\n", &Config{ max_line_length: 11, ..mk_args(2) });
+    }
+
+    #[test]
+    fn test_wrap_tolerance() {
+        let mk_args = |wrap_tolerance_: usize| Config{ max_line_length: 10, wrap_tolerance: wrap_tolerance_, ..Config::default() };
+        test_read_lines(b"foo bar baz\n", b"foo bar baz\n", &mk_args(3));
+        test_read_lines(b"foo bar\nbaz\n", b"foo bar baz\n", &mk_args(0));
+        test_read_lines(b"foo bar baz\nqux\n", b"foo bar baz qux\n", &mk_args(3));
+        test_read_lines(b"foo bar\nbazqux\n", b"foo bar bazqux\n", &mk_args(3));
+    }
+
+    #[test]
+    fn test_first_line_width() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 12, first_line_width: Some(6), rewrap: rewrap_, ..Config::default() };
+        let input = b"Note: foo bar baz qux quux corge\nnext\n";
+        test_read_lines(b"Note:\nfoo bar baz\nqux quux\ncorge\nnext\n", input, &mk_args(false));
+        test_read_lines(b"Note:\nfoo bar baz\nqux quux\ncorge next\n", input, &mk_args(true));
+        test_read_lines(b"Note:\nfoo bar baz\n", b"Note: foo bar baz\n", &Config{ justify: true, ..mk_args(false) });
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mk_args = |ellipsis_: Option<&str>| Config{ max_line_length: 8, truncate: true, ellipsis: ellipsis_.map(str::to_owned), ..Config::default() };
+        let input = "foo bar baz\nqux\nabcdefghijk\n".as_bytes();
+        test_read_lines(b"foo bar\nqux\nabcdefgh\n", input, &mk_args(None));
+        test_read_lines("foo bar…\nqux\nabcdefg…\n".as_bytes(), input, &mk_args(Some("…")));
+        test_read_lines(b"foo b...\nqux\nabcde...\n", input, &mk_args(Some("...")));
+        let args = Config{ width_mode: WidthMode::Display, ansi: AnsiMode::Ignore, ..mk_args(Some("…")) };
+        test_read_lines("\x1b[1m世界\x1b[0m世…\n".as_bytes(), "\x1b[1m世界\x1b[0m世界世\n".as_bytes(), &args);
+    }
+
+    #[test]
+    fn test_markdown() {
+        let mk_args = |markdown_: bool, break_words_: bool| Config{ max_line_length: 12, markdown: markdown_, break_words: break_words_, ..Config::default() };
+        let input = b"Call `foo bar baz` or see [the docs](docs/foo bar.md) and ``a ` b``\n";
+        test_read_lines(b"Call\n`foo bar baz`\nor see [the\ndocs](docs/foo bar.md)\nand\n``a ` b``\n", input, &mk_args(true, false));
+        test_read_lines(b"Call `foo\nbar baz` or\nsee [the\ndocs](docs/foo\nbar.md) and\n``a ` b``\n", input, &mk_args(false, false));
+        test_read_lines(b"Call\n`foo bar baz`\nor see [the\ndocs](docs/foo bar.md)\nand\n``a ` b``\n", input, &mk_args(true, true));
+        test_read_lines(b"see `a\nb`\n", b"see `a\nb`\n", &mk_args(true, false));
+    }
+
+    #[test]
+    fn test_extra_break_chars() {
+        let mk_args = |extra_break_chars_: &str| Config{ max_line_length: 12, extra_break_chars: extra_break_chars_.to_owned(), ..Config::default() };
+        let input = b"see /usr/local/share/foo,barbaz\n";
+        test_read_lines(b"see /usr/\nlocal/share/\nfoo,barbaz\n", input, &mk_args("/"));
+        test_read_lines(b"see /usr/\nlocal/share/\nfoo,\nbarbazqux\n", b"see /usr/local/share/foo,barbazqux\n", &mk_args("/,"));
+        test_read_lines(b"see\n/usr/local/share/foo,barbaz\n", input, &mk_args(""));
+    }
+
+    #[test]
+    fn test_line_wrapping_with_no_break_chars() {
+        let mk_args = |no_break_chars_: &str| Config{ max_line_length: 9, no_break_chars: no_break_chars_.to_owned(), ..Config::default() };
+        test_read_lines("It weighs\n10\u{a0}kg\nsays\nMr.\u{202f}Smith\n".as_bytes(), "It weighs 10\u{a0}kg says Mr.\u{202f}Smith\n".as_bytes(), &mk_args("\u{a0}\u{202f}"));
+        test_read_lines("It weighs\n10\u{a0}kg\n".as_bytes(), "It weighs 10\u{a0}kg\n".as_bytes(), &Config{ max_line_length: 9, ..Config::default() });
+        test_read_lines("10\nkg\n".as_bytes(), "10\u{a0}kg\n".as_bytes(), &Config{ max_line_length: 3, ..mk_args("") });
+    }
+
+    #[test]
+    fn test_list_handling_without_preserving_indentation() {
+        let mk_args = |max_line_length_: usize|
+            Config{ max_line_length: max_line_length_, ..Config::default() };
+        test_read_lines(b"A list\nfollows:\n* foo bar baz\n* test1 test2\ntest3 test4\n", b"A list follows:\n* foo bar baz\n* test1 test2 test3 test4\n", &mk_args(14));
+        test_read_lines(b"A list\nfollows:\n* foo bar baz\n* test1 test2\ntest3 test4\n", b"A list follows:\n* foo bar baz\n* test1 test2 test3 test4\n", &mk_args(13));
+    }
+
+    #[test]
+    fn test_list_handling_with_preserving_indentation() {
+        let mk_args = |max_line_length_: usize|
+            Config{ max_line_length: max_line_length_, preserve_list_indentation: true, ..Config::default() };
+        test_read_lines(b"A list\nfollows:\n* foo bar baz\n* test1 test2\n  test3 test4\n", b"A list follows:\n* foo bar baz\n* test1 test2 test3 test4\n", &mk_args(13));
+        test_read_lines(b"A list\nfollows:\n* foo bar baz\n  * test1\n    test2\n    test3\n    test4\n", b"A list follows:\n* foo bar baz\n  * test1 test2 test3 test4\n", &mk_args(13));
+        test_read_lines(b"A list follows:\n* foo bar baz\n  * test1 test2\n    test3 test4\n", b"A list follows:\n* foo bar baz\n  * test1 test2 test3 test4\n", &mk_args(15));
+    }
+
+    #[test]
+    fn test_numbered_list_handling_with_preserving_indentation() {
+        let mk_args = |max_line_length_: usize|
+            Config{ max_line_length: max_line_length_, preserve_list_indentation: true, ..Config::default() };
+        test_read_lines(b"10. some very long\n    wrapped text\n", b"10. some very long wrapped text\n", &mk_args(20));
+        test_read_lines(b"1) foo\n   bar\n  2. foo\n     bar\n", b"1) foo bar\n  2. foo bar\n", &mk_args(9));
+        test_read_lines(b"1.5 is\nnot a\nlist\n", b"1.5 is not a list\n", &mk_args(6));
+    }
+
+    #[test]
+    fn test_custom_list_markers() {
+        let mk_args = |max_line_length_: usize, rewrap_: bool|
+            Config{ max_line_length: max_line_length_, preserve_list_indentation: true, list_markers: String::from("+•"), rewrap: rewrap_, ..Config::default() };
+        test_read_lines("+ foo\n  bar\n• foo\n  bar\n".as_bytes(), "+ foo bar\n• foo bar\n".as_bytes(), &mk_args(6, false));
+        test_read_lines(b"* foo\nbar\n", b"* foo bar\n", &mk_args(6, false));
+        test_read_lines("foo\n+ bar\n• baz\n".as_bytes(), "foo\n+ bar\n• baz\n".as_bytes(), &mk_args(0, true));
+    }
+
+    #[test]
+    fn test_list_indentation_normalization() {
+        let mk_args = |max_line_length_: usize, rewrap_: bool|
+            Config{ max_line_length: max_line_length_, normalize_list_indentation: true, rewrap: rewrap_, ..Config::default() };
+        test_read_lines(b"* foo\n  bar\n  * baz\n    qux\n* end\n  x\n\nafter\n", b"* foo\n    bar\n  * baz\n qux\n* end\nx\n\nafter\n", &mk_args(0, false));
+        test_read_lines(b"1. foo bar\n   baz\n", b"1. foo bar\n baz\n", &mk_args(0, false));
+        test_read_lines(b"* foo bar\n  baz qux\n", b"* foo\n    bar baz qux\n", &mk_args(12, true));
+        test_read_lines(b"* foo bar\n  * baz qux\n    quux\n    corge\n", b"* foo bar\n  * baz qux\nquux corge\n", &mk_args(12, true));
+    }
+
+    #[test]
+    fn test_blockquotes() {
+        let mk_args = |max_line_length_: usize, rewrap_: bool|
+            Config{ max_line_length: max_line_length_, preserve_blockquotes: true, rewrap: rewrap_, ..Config::default() };
+        test_read_lines(b"> > deeply\n> > quoted\n> > long text\n", b"> > deeply quoted long text\n", &mk_args(14, false));
+        test_read_lines(b">> deeply\n>> quoted\n", b">> deeply quoted\n", &mk_args(10, false));
+        test_read_lines(b"> foo bar\n> > baz qux\nend\n", b"> foo\n> bar\n> > baz\n> > qux\nend\n", &mk_args(0, true));
+        test_read_lines(b"> foo bar\n> baz\n", b"> foo bar\n> baz\n", &mk_args(9, true));
+    }
+
+    #[test]
+    fn test_rewrapping() {
+        let mk_args = |max_line_length_: usize|
+            Config{ max_line_length: max_line_length_, preserve_list_indentation: true, rewrap: true, ..Config::default() };
+        test_read_lines(b"A list follows:\n* foo bar baz\n  * test1 test2\n    test3 test4\n", b"A list follows:\n* foo bar baz\n  * test1 test2 test3 test4\n", &mk_args(15));
+        test_read_lines(b"A list follows:\n* foo bar baz\n  * test1 test2\n    test3 test4\n", b"A list\nfollows:\n* foo\n  bar baz\n  * test1 test2 test3 test4\n", &mk_args(15));
+        test_read_lines(b"A list follows:\n* foo bar baz\n  * test1 test2 test3 test4\n", b"A list\nfollows:\n* foo\n  bar baz\n  * test1 test2 test3 test4\n", &mk_args(0));
+        test_read_lines(b"foo bar\nbaz qux\n", b"foo bar\nbaz qux\n", &mk_args(9));
+        // no additional line is written at the end for the paragraph if it has already been flushed
+        test_read_lines(b"foo bar baz\n\n", b"foo bar\nbaz\n\n", &mk_args(20));
+        test_read_lines(b"foo bar baz\n\n\n", b"foo bar\nbaz\n\n\n", &mk_args(20));
+    }
+
+    #[test]
+    fn test_rewrapping_with_substituted_list_markers() {
+        let mk_args = |replacement_: &str| Config{
+            max_line_length: 20, preserve_list_indentation: true, rewrap: true,
+            substitute_regex: vec!["^o ".to_owned()], replacement: vec![replacement_.to_owned()], ..Config::default() };
+        let input = b"Items:\no foo\no bar\n";
+        test_read_lines(b"Items:\n* foo\n* bar\n", input, &mk_args("* "));
+        test_read_lines(b"Items: foo bar\n", input, &mk_args(""));
+    }
+
+    #[test]
+    fn test_page_separator() {
+        let mk_args = |rewrap_: bool, page_separator_: bool| Config{ max_line_length: 8, rewrap: rewrap_, page_separator: page_separator_, ..Config::default() };
+        let input = b"foo bar baz \x0cqux\nqu\n";
+        test_read_lines(b"foo bar\nbaz\n\x0cqux\nqu\n", input, &mk_args(false, true));
+        test_read_lines(b"foo bar\nbaz\n\x0cqux qu\n", input, &mk_args(true, true));
+        test_read_lines(b"foo bar\nbaz \x0cqux\nqu\n", input, &mk_args(true, false));
+        test_read_lines(b"foo\n\x0cbar\n", b"foo\n\x0cbar\n", &mk_args(true, true));
+    }
+
+    #[test]
+    fn test_rewrapping_numbered_lists() {
+        let mk_args = |max_line_length_: usize|
+            Config{ max_line_length: max_line_length_, preserve_list_indentation: true, rewrap: true, ..Config::default() };
+        test_read_lines(b"Steps:\n1. foo bar baz\n2. qux\n10) quux corge\n", b"Steps:\n1. foo\nbar baz\n2. qux\n10) quux\ncorge\n", &mk_args(20));
+        test_read_lines(b"1. foo\n   bar\n2. baz\n", b"1. foo bar\n2. baz\n", &mk_args(6));
+        test_read_lines(b"version 1.5 is\nnot a list\n", b"version\n1.5 is not a list\n", &mk_args(14));
+    }
+
+    #[test]
+    fn test_rewrapping_final_paragraph() {
+        let mk_args = |justify_: bool| Config{ max_line_length: 7, rewrap: true, justify: justify_, ..Config::default() };
+        test_read_lines(b"foo bar\nbaz qux\nquux\ncorge\nabcdefghij\nklm", b"foo bar\nbaz qux quux corge\nabcdefghij klm", &mk_args(false));
+        test_read_lines(b"foo bar\nbaz qux\nquux\ncorge\n", b"foo\nbar baz qux quux corge\n", &mk_args(true));
+    }
+
+    #[test]
+    fn test_rewrapping_paragraphs() {
+        let mk_args = |preserve_blockquotes_: bool|
+            Config{ rewrap: true, preserve_blockquotes: preserve_blockquotes_, ..Config::default() };
+        test_read_lines(b"foo bar\n\nbaz qux\n", b"foo\nbar\n\nbaz\nqux\n", &mk_args(false));
+        test_read_lines(b"foo\n\n\nbar\n\n", b"foo\n\n\nbar\n\n", &mk_args(false));
+        test_read_lines(b"> foo bar\n>\n> baz qux\n", b"> foo\n> bar\n>\n> baz\n> qux\n", &mk_args(true));
+    }
+
+    #[test]
+    fn test_reading_input_files() {
+        let input_file_paths = Vec::from([String::from("testfiles/testinput1"), String::from("testfiles/testinput2")]);
+        let mk_args = |max_line_length_: usize|
+        Config{ max_line_length: max_line_length_, preserve_list_indentation: true, rewrap: true, input_files: input_file_paths, ..Config::default() };
+        test_read_lines(b"foo bar 1 2 3 4\n5 6 7 8 9 10 11\n12\n", b"", &mk_args(15));
+    }
+
+    #[test]
+    fn test_line_wrapping_with_multibyte_characters() {
+        let mk_args = |max_line_length_: usize, break_words_: bool|
+            Config{ max_line_length: max_line_length_, break_words: break_words_, ..Config::default() };
+        test_read_lines("äää\nööö\nüüü\n".as_bytes(), "äää ööö üüü\n".as_bytes(), &mk_args(4, false));
+        test_read_lines("äää\nööö\nüüü\n".as_bytes(), "äää ööö üüü\n".as_bytes(), &mk_args(4, true));
+        test_read_lines("äää\u{a0}ööö\n".as_bytes(), "äää\u{a0}ööö\n".as_bytes(), &mk_args(5, false));
+    }
+
+    #[test]
+    fn test_line_wrapping_with_display_width() {
+        let mk_args = |max_line_length_: usize, width_mode_: WidthMode, break_words_: bool|
+            Config{ max_line_length: max_line_length_, width_mode: width_mode_, break_words: break_words_, ..Config::default() };
+        // wide characters occupy two columns
+        test_read_lines("世界 世界\n".as_bytes(), "世界 世界\n".as_bytes(), &mk_args(6, WidthMode::Chars, false));
+        test_read_lines("世界\n世界\n".as_bytes(), "世界 世界\n".as_bytes(), &mk_args(6, WidthMode::Display, false));
+        test_read_lines("世界，\n世界\n".as_bytes(), "世界，世界\n".as_bytes(), &mk_args(6, WidthMode::Display, true));
+        // combining marks and zero-width joiners occupy no columns and are kept with the preceding character
+        test_read_lines("e\u{301}e\u{301}\ne\u{301}\n".as_bytes(), "e\u{301}e\u{301}e\u{301}\n".as_bytes(), &mk_args(2, WidthMode::Display, true));
+        test_read_lines("a\u{200d}b\n".as_bytes(), "a\u{200d}b\n".as_bytes(), &mk_args(2, WidthMode::Display, true));
+        test_read_lines("a\u{200d}\nb\n".as_bytes(), "a\u{200d}b\n".as_bytes(), &mk_args(2, WidthMode::Chars, true));
+    }
+
+    #[test]
+    fn test_line_wrapping_with_byte_width() {
+        let mk_args = |max_line_length_: usize, break_words_: bool|
+            Config{ max_line_length: max_line_length_, width_mode: WidthMode::Bytes, break_words: break_words_, ..Config::default() };
+        // multibyte characters are never split even if only some of their bytes would fit
+        test_read_lines("aaaa\nää\n".as_bytes(), "aaaaää\n".as_bytes(), &mk_args(5, true));
+        test_read_lines("aäa\nä世\n".as_bytes(), "aäaä世\n".as_bytes(), &mk_args(5, true));
+        test_read_lines("ää\nöö\n".as_bytes(), "ää öö\n".as_bytes(), &mk_args(5, false));
+        test_read_lines("ää öö\n".as_bytes(), "ää öö\n".as_bytes(), &mk_args(9, false));
+    }
+
+    #[test]
+    fn test_reading_stdin_between_input_files() {
+        let input_file_paths = vec![String::from("testfiles/testinput1"), String::from("-"), String::from("testfiles/testinput1")];
+        let config = Config{ input_files: input_file_paths, ..Config::default() };
+        test_read_lines(b"foo\nbar\nfrom stdin\nfoo\nbar\n", b"from stdin\n", &config);
+    }
+
+    #[test]
+    fn test_in_place_editing() {
+        let dir = std::env::temp_dir().join(format!("formatter-test-in-place-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("input").to_str().unwrap().to_owned();
+        let invalid_file_path = dir.join("invalid").to_str().unwrap().to_owned();
+        fs::write(&file_path, "foo bar baz\n").unwrap();
+        fs::write(&invalid_file_path, b"foo b\xffr\n").unwrap();
+
+        let config = Config{ max_line_length: 7, in_place: true, backup_suffix: Some(String::from(".bak")), input_files: vec![invalid_file_path.clone(), file_path.clone()], ..Config::default() };
+        let mut output = Vec::new();
+        let result = format(&config, &mut Cursor::new(""), &mut output);
+        assert!(matches!(result, Err(FormatError::FileRead { path, .. }) if path == invalid_file_path));
+        assert!(output.is_empty());
+        assert_eq!("foo bar\nbaz\n", fs::read_to_string(&file_path).unwrap());
+        assert_eq!("foo bar baz\n", fs::read_to_string(format!("{}.bak", file_path)).unwrap());
+        assert_eq!(b"foo b\xffr\n".to_vec(), fs::read(&invalid_file_path).unwrap());
+        assert!(!Path::new(&format!("{}.bak", invalid_file_path)).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_output_dir() {
+        let dir = std::env::temp_dir().join(format!("formatter-test-output-dir-{}", std::process::id()));
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        let output_dir = dir.join("out").join("formatted").to_str().unwrap().to_owned();
+        let file_path = dir.join("a").join("input1").to_str().unwrap().to_owned();
+        let other_file_path = dir.join("b").join("input2").to_str().unwrap().to_owned();
+        let colliding_file_path = dir.join("b").join("input1").to_str().unwrap().to_owned();
+        for path in [&file_path, &other_file_path, &colliding_file_path] {
+            fs::write(path, "foo bar baz\n").unwrap();
+        }
+
+        let mk_args = |input_files_: Vec<String>| Config{ max_line_length: 7, output_dir: Some(output_dir.clone()), input_files: input_files_, ..Config::default() };
+        let mut output = Vec::new();
+        assert!(format(&mk_args(vec![file_path.clone(), other_file_path.clone()]), &mut Cursor::new(""), &mut output).is_ok());
+        assert!(output.is_empty());
+        assert_eq!("foo bar\nbaz\n", fs::read_to_string(Path::new(&output_dir).join("input1")).unwrap());
+        assert_eq!("foo bar\nbaz\n", fs::read_to_string(Path::new(&output_dir).join("input2")).unwrap());
+        assert_eq!("foo bar baz\n", fs::read_to_string(&file_path).unwrap());
+
+        let result = format(&mk_args(vec![file_path.clone(), colliding_file_path.clone()]), &mut Cursor::new(""), &mut output);
+        assert!(matches!(result, Err(FormatError::Config(ConfigError::OutputFileCollision { path, other_path, .. })) if path == colliding_file_path && other_path == file_path));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check() {
+        let mk_args = |input_files_: Vec<String>| Config{ max_line_length: 7, check: true, input_files: input_files_, ..Config::default() };
+        let mut output = Vec::new();
+        assert!(format(&mk_args(Vec::new()), &mut Cursor::new("foo bar\nbaz\n"), &mut output).is_ok());
+        assert!(matches!(format(&mk_args(Vec::new()), &mut Cursor::new("foo bar baz\n"), &mut output), Err(FormatError::NotFormatted { path }) if path == "<stdin>"));
+
+        let dir = std::env::temp_dir().join(format!("formatter-test-check-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let formatted_file_path = dir.join("formatted").to_str().unwrap().to_owned();
+        let unformatted_file_path = dir.join("unformatted").to_str().unwrap().to_owned();
+        fs::write(&formatted_file_path, "foo bar\nbaz\n").unwrap();
+        fs::write(&unformatted_file_path, "foo bar baz\n").unwrap();
+        let result = format(&mk_args(vec![unformatted_file_path.clone(), formatted_file_path.clone(), "-".to_owned()]), &mut Cursor::new("foo\n"), &mut output);
+        assert!(matches!(result, Err(FormatError::NotFormatted { path }) if path == unformatted_file_path));
+        assert!(format(&mk_args(vec![formatted_file_path.clone()]), &mut Cursor::new(""), &mut output).is_ok());
+        assert_eq!("foo bar baz\n", fs::read_to_string(&unformatted_file_path).unwrap());
+        assert!(output.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let cli = parse_cli(["formatter", "-m", "7", "--check"]);
+        assert_eq!(1, run_cli(&cli, &mut output, &mut Cursor::new("foo bar baz\n")));
+        assert_eq!(0, run_cli(&cli, &mut output, &mut Cursor::new("foo\n")));
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_diff() {
+        let mk_args = |max_line_length_: usize| Config{ max_line_length: max_line_length_, diff: true, ..Config::default() };
+        let mut output = Vec::new();
+        assert!(format(&mk_args(7), &mut Cursor::new("foo bar\nbaz\n"), &mut output).is_ok());
+        assert!(output.is_empty());
+
+        let input = "1\n2\n3\n4\nfoo bar baz\n5\n6\n7\n8\n9\n10\n11\nfoo bar baz";
+        assert!(matches!(format(&mk_args(7), &mut Cursor::new(input), &mut output), Err(FormatError::NotFormatted { .. })));
+        assert_eq!(concat!(
+            "--- <stdin>\n+++ <stdin>\n",
+            "@@ -2,7 +2,8 @@\n 2\n 3\n 4\n-foo bar baz\n+foo bar\n+baz\n 5\n 6\n 7\n",
+            "@@ -10,4 +11,5 @@\n 9\n 10\n 11\n-foo bar baz\n\\ No newline at end of file\n+foo bar\n+baz\n\\ No newline at end of file\n",
+        ), String::from_utf8(output).unwrap());
+
+        let mut output = Vec::new();
+        assert!(format(&mk_args(3), &mut Cursor::new("foo bar\n"), &mut output).is_err());
+        assert_eq!("--- <stdin>\n+++ <stdin>\n@@ -1,1 +1,2 @@\n-foo bar\n+foo\n+bar\n", String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn test_concatenate_and_separate() {
+        let dir = std::env::temp_dir().join(format!("formatter-test-separate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file_paths: Vec<String> = (0..2).map(|index| dir.join(index.to_string()).to_str().unwrap().to_owned()).collect();
+        fs::write(&file_paths[0], "foo bar\nbaz\n").unwrap();
+        fs::write(&file_paths[1], "qux quux\n\nfoo\n").unwrap();
+        let mk_args = |concatenate_: bool, separate_: bool| Config{ max_line_length: 20, rewrap: true, concatenate: concatenate_, separate: separate_, input_files: file_paths.clone(), ..Config::default() };
+
+        for (expected, args) in [
+            ("foo bar baz qux quux\n\nfoo\n", mk_args(false, false)),
+            ("foo bar baz qux quux\n\nfoo\n", mk_args(true, false)),
+            ("foo bar baz\nqux quux\n\nfoo\n", mk_args(false, true)),
+        ] {
+            let mut output = Vec::new();
+            assert!(format(&args, &mut Cursor::new(""), &mut output).is_ok());
+            assert_eq!(expected, String::from_utf8(output).unwrap());
+        }
+        assert!(parse_cli(["formatter", "--flush-between-files"]).config.separate);
+        assert!(!parse_cli(["formatter", "--flush-between-files", "--concatenate"]).config.separate);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_json_output() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 7, rewrap: rewrap_, number_lines: true, number_width: 1, output_format: OutputFormat::Json, ..Config::default() };
+        let expected = concat!(
+            r#"[{"source_line":1,"output":"1\tfoo bar","wrapped":true},{"source_line":1,"output":"2\tbaz","wrapped":true},"#,
+            r#"{"source_line":2,"output":"3\t","wrapped":false},{"source_line":3,"output":"4\t\"qux\"","wrapped":false}]"#, "\n");
+        assert_eq!(expected, format_string(&mk_args(false), "foo bar baz\n\n\"qux\"").unwrap());
+        let expected = concat!(
+            r#"[{"source_line":1,"output":"1\tfoo bar","wrapped":true},{"source_line":1,"output":"2\tbaz qux","wrapped":true},"#,
+            r#"{"source_line":2,"output":"3\tquux","wrapped":true}]"#, "\n");
+        assert_eq!(expected, format_string(&mk_args(true), "foo bar baz\nqux quux\n").unwrap());
+        assert_eq!("[]\n", format_string(&mk_args(false), "").unwrap());
+    }
+
+    #[test]
+    fn test_null() {
+        let mk_args = |max_line_length_: usize| Config{ max_line_length: max_line_length_, null: true, ..Config::default() };
+        test_read_lines(b"foo\nbar\0baz\0", b"foo\nbar\0baz\0", &mk_args(0));
+        test_read_lines(b"foo\0bar\0baz\0", b"foo\nbar baz\0", &mk_args(4));
+        test_read_lines(b"foo\0bar", b"foo bar", &mk_args(4));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mk_args = |substitute_multiline_: bool| Config{
+            max_line_length: 7, substitute_regex: vec!["o".to_owned()], replacement: vec!["0".to_owned()], substitute_count: vec![0],
+            substitute_multiline: substitute_multiline_, ..Config::default() };
+        for args in [mk_args(false), mk_args(true)] {
+            let mut output = Vec::new();
+            let stats = format_with_stats(&args, &mut Cursor::new("foo bar baz\nfoo"), &mut output).unwrap();
+            assert_eq!(b"f00 bar\nbaz\nf00", output.as_slice());
+            assert_eq!(Stats { lines_read: 2, lines_written: 3, wraps: 1, long_words: 0, substitutions: 4, bytes_read: 15, bytes_written: 15 }, stats);
+        }
+        let stats = Stats { lines_read: 1, lines_written: 2, wraps: 3, long_words: 7, substitutions: 4, bytes_read: 5, bytes_written: 6 };
+        assert_eq!("lines_read=1\nlines_written=2\nwraps=3\nlong_words=7\nsubstitutions=4\nbytes_read=5\nbytes_written=6\n", stats.to_string());
+    }
+
+    #[test]
+    fn test_warn_long_words() {
+        let mk_args = |break_words_: bool| Config{ max_line_length: 5, break_words: break_words_, warn_long_words: true, ..Config::default() };
+        let input = "foo abcdefgh\nbar ijklmnop qrstuvwx\n";
+        let mut output = Vec::new();
+        let stats = format_with_stats(&mk_args(false), &mut Cursor::new(input), &mut output).unwrap();
+        assert_eq!(b"foo\nabcdefgh\nbar\nijklmnop\nqrstuvwx\n", output.as_slice());
+        assert_eq!(3, stats.long_words);
+        let stats = format_with_stats(&mk_args(true), &mut Cursor::new(input), &mut Vec::new()).unwrap();
+        assert_eq!(0, stats.long_words);
+
+        // the notice names the input file and the line number within it
+        let args = mk_args(false);
+        let mut output = OutputState::new(Vec::new(), &args);
+        let mut paragraph = ParagraphState::default();
+        let mut process = |output: &mut OutputState<Vec<u8>>, line: &str| process_line(output, &mut line.to_owned(), &mut paragraph, &args, &[], &mut String::new()).unwrap();
+        output.start_input("foo.txt");
+        process(&mut output, "foo");
+        process(&mut output, "bar abcdefgh");
+        output.start_input("-");
+        process(&mut output, "ijklmnop");
+        assert_eq!(vec![
+            "\"foo.txt\" line 2 contains a word exceeding the maximum line length".to_owned(),
+            "\"<stdin>\" line 1 contains a word exceeding the maximum line length".to_owned(),
+        ], output.warnings);
+        output.report_warnings(&Config{ quiet: true, ..Config::default() });
+        assert!(output.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_jobs() {
+        let dir = std::env::temp_dir().join(format!("formatter-test-jobs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file_paths: Vec<String> = (0..8).map(|index| dir.join(index.to_string()).to_str().unwrap().to_owned()).collect();
+        for (index, file_path) in file_paths.iter().enumerate() {
+            fs::write(file_path, format!("foo {} bar baz\n", index)).unwrap();
+        }
+        let mk_args = |in_place_: bool, input_files_: Vec<String>| Config{ max_line_length: 7, jobs: 3, in_place: in_place_, input_files: input_files_, ..Config::default() };
+
+        let mut input_files = file_paths.clone();
+        input_files.insert(2, "-".to_owned());
+        input_files.insert(4, dir.join("missing").to_str().unwrap().to_owned());
+        let mut output = Vec::new();
+        let result = format(&mk_args(false, input_files), &mut Cursor::new("stdin input\n"), &mut output);
+        assert!(matches!(result, Err(FormatError::FileOpen { .. })));
+        let mut expected = (0..8).map(|index| format!("foo {}\nbar baz\n", index)).collect::<Vec<_>>();
+        expected.insert(2, "stdin\ninput\n".to_owned());
+        assert_eq!(expected.concat(), String::from_utf8(output).unwrap());
+
+        // the line ending omitted at the end of a file without final newline is still written when output follows
+        let no_final_newline_path = dir.join("no-final-newline").to_str().unwrap().to_owned();
+        fs::write(&no_final_newline_path, "alpha beta").unwrap();
+        let input_files = vec![no_final_newline_path.clone(), file_paths[0].clone(), no_final_newline_path];
+        for jobs_ in [1, 3] {
+            let config = Config{ jobs: jobs_, ..mk_args(false, input_files.clone()) };
+            assert_eq!("alpha\nbeta\nfoo 0\nbar baz\nalpha\nbeta", format_string(&config, "").unwrap());
+        }
+
+        let mut output = Vec::new();
+        assert!(format(&mk_args(true, file_paths.clone()), &mut Cursor::new(""), &mut output).is_ok());
+        assert!(output.is_empty());
+        for (index, file_path) in file_paths.iter().enumerate() {
+            assert_eq!(format!("foo {}\nbar baz\n", index), fs::read_to_string(file_path).unwrap());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_formatter() {
+        let mut formatter = Formatter::new(Config{ max_line_length: 7, rewrap: true, ..Config::default() }).unwrap();
+        assert!(formatter.push_line("foo").is_empty());
+        assert_eq!(vec!["foo bar".to_owned()], formatter.push_line("bar baz"));
+        assert_eq!(vec!["baz".to_owned(), String::new()], formatter.push_line(""));
+        assert_eq!(vec!["foo bar".to_owned()], formatter.push_line("foo bar baz"));
+        assert_eq!(vec!["baz".to_owned()], formatter.finish());
+        assert!(formatter.finish().is_empty());
+
+        let mut formatter = Formatter::new(Config{ max_line_length: 3, number_lines: true, number_width: 1, ..Config::default() }).unwrap();
+        assert_eq!(vec!["1\tfoo".to_owned(), "2\tbar".to_owned()], formatter.push_line("foo bar"));
+        assert_eq!(vec!["3\t".to_owned()], formatter.push_line(""));
+        assert!(formatter.finish().is_empty());
+
+        assert!(matches!(Formatter::new(Config{ substitute_regex: vec!["(".to_owned()], ..Config::default() }), Err(FormatError::Config(ConfigError::Regex { .. }))));
+    }
+
+    #[test]
+    fn test_output_file() {
+        let output_path = std::env::temp_dir().join(format!("formatter-test-output-{}", std::process::id()));
+        let output_path = output_path.to_str().unwrap();
+        fs::write(output_path, "previous contents which are longer\n").unwrap();
+
+        let mut output = Vec::new();
+        let cli = parse_cli(["formatter", "-m", "7", "-o", output_path]);
+        assert_eq!(0, run_cli(&cli, &mut output, &mut Cursor::new("foo bar baz\n")));
+        assert!(output.is_empty());
+        assert_eq!("foo bar\nbaz\n", fs::read_to_string(output_path).unwrap());
+        fs::remove_file(output_path).unwrap();
+
+        let cli = parse_cli(["formatter", "-o", "testfiles/missing-dir/output"]);
+        assert_eq!(1, run_cli(&cli, &mut output, &mut Cursor::new("foo\n")));
+    }
+
+    #[test]
+    fn test_justify() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 20, rewrap: rewrap_, justify: true, preserve_list_indentation: true, ..Config::default() };
+        test_read_lines(b"the  quick brown fox\njumps  over the lazy\ndog     and    keeps\nrunning\n", b"the quick brown fox jumps over the lazy dog and keeps running\n", &mk_args(false));
+        test_read_lines(b"the  quick brown fox\njumps  over the lazy\ndog\n", b"the quick brown\nfox jumps over the lazy dog\n", &mk_args(true));
+        test_read_lines(b"- item  one has some\n  words that wrap\n  around\n", b"- item one has some words that wrap around\n", &mk_args(false));
+        test_read_lines(b"supercalifragilisticexpialidocious\nis long\n", b"supercalifragilisticexpialidocious is long\n", &mk_args(false));
+    }
+
+    #[test]
+    fn test_align() {
+        let mk_args = |max_line_length_: usize, align_: Alignment, keep_trailing_whitespaces_: bool|
+            Config{ max_line_length: max_line_length_, align: align_, keep_trailing_whitespaces: keep_trailing_whitespaces_, ..Config::default() };
+        test_read_lines(b"   Title\n\n sub title\nfoo bar baz\n", b"Title\n\n  sub title\nfoo bar baz\n", &mk_args(11, Alignment::Center, false));
+        test_read_lines(b"   foo  \n", b"foo  \n", &mk_args(9, Alignment::Center, true));
+        test_read_lines(b"  foo\n", b"  foo\n", &mk_args(0, Alignment::Center, false));
+        test_read_lines(b"    1.5\n 100.25\n\n1000000\n12345678\n", b"1.5\n100.25\n\n1000000\n12345678\n", &mk_args(7, Alignment::Right, false));
+        test_read_lines(b"  foo\n1\n", b"  foo\n1\n", &mk_args(5, Alignment::Left, false));
+    }
+
+    #[test]
+    fn test_squeeze_blank() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 7, rewrap: rewrap_, squeeze_blank: true, ..Config::default() };
+        test_read_lines(b"foo\n\nbar\n\nbaz\n", b"foo\n\n \n\t\nbar\n\nbaz\n", &mk_args(false));
+        test_read_lines(b"\nfoo bar\nbaz\n\nfoo\n\n", b"\n\nfoo\nbar baz\n\n\n\nfoo\n\n\n", &mk_args(true));
+    }
+
+    #[test]
+    fn test_ensure_final_newline() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 7, rewrap: rewrap_, ensure_final_newline: true, ..Config::default() };
+        for input in ["foo bar baz", "foo bar baz\n", "foo bar baz\n\n\n", "foo bar baz\n \n\t\n"] {
+            test_read_lines(b"foo bar\nbaz\n", input.as_bytes(), &mk_args(false));
+            test_read_lines(b"foo bar\nbaz\n", input.as_bytes(), &mk_args(true));
+        }
+        test_read_lines(b"\nfoo\n\nbar\n", b"\nfoo\n\nbar\n\n", &mk_args(false));
+        test_read_lines(b"", b"", &mk_args(false));
+        test_read_lines(b"", b"\n\n", &mk_args(false));
+    }
+
+    #[test]
+    fn test_max_blank_lines() {
+        let mk_args = |max_blank_lines_: usize, squeeze_blank_: bool| Config{ max_blank_lines: Some(max_blank_lines_), squeeze_blank: squeeze_blank_, ..Config::default() };
+        test_read_lines(b"foo\n\n\nbar\n\n\n", b"foo\n\n\n \n\n\nbar\n\n\n\n\n\n", &mk_args(2, false));
+        test_read_lines(b"foo\n\nbar\n\n", b"foo\n\n\n \n\n\nbar\n\n\n\n\n\n", &mk_args(2, true));
+        test_read_lines(b"foo\nbar\n", b"foo\n\n\n \n\n\nbar\n\n\n\n\n\n", &mk_args(0, false));
+    }
+
+    #[test]
+    fn test_remove_blank_lines() {
+        let mk_args = |rewrap_: bool, squeeze_blank_: bool| Config{ max_line_length: 7, rewrap: rewrap_, remove_blank_lines: true, squeeze_blank: squeeze_blank_, ..Config::default() };
+        test_read_lines(b"foo\nbar\nbaz\n", b"\nfoo\n\n \n\t\nbar\n\nbaz\n", &mk_args(false, false));
+        test_read_lines(b"foo\nbar\nbaz\n", b"\nfoo\n\n \n\t\nbar\n\nbaz\n", &mk_args(false, true));
+        test_read_lines(b"foo bar\nbaz\nfoo\n", b"\nfoo\nbar baz\n\n\n\nfoo\n\n", &mk_args(true, false));
+    }
+
+    #[test]
+    fn test_collapse_whitespace() {
+        let mk_args = |max_line_length_: usize| Config{ max_line_length: max_line_length_, collapse_whitespace: true, preserve_list_indentation: true, ..Config::default() };
+        test_read_lines(b"foo bar baz\n", b"foo    bar\t\tbaz\n", &mk_args(0));
+        test_read_lines(b"  - foo bar\n    baz\n", b"  -   foo \xc2\xa0 bar  baz  \n", &mk_args(11));
+    }
+
+    #[test]
+    fn test_normalize_sentence_spacing() {
+        let mk_args = |max_line_length_: usize| Config{ max_line_length: max_line_length_, normalize_sentence_spacing: true, preserve_list_indentation: true, ..Config::default() };
+        test_read_lines(b"Done. Next! \n", b"Done.  Next!  \n", &Config{ keep_trailing_whitespaces: true, ..mk_args(0) });
+        test_read_lines(b"  Done? Next  to\n", b"  Done?   Next  to\n", &mk_args(0));
+        test_read_lines(b"1.  Done. Next\n    one\n", b"1.  Done.  Next one\n", &mk_args(14));
+    }
+
+    #[test]
+    fn test_trim_leading() {
+        let mk_args = |max_line_length_: usize| Config{ max_line_length: max_line_length_, trim_leading: true, preserve_list_indentation: true, ..Config::default() };
+        test_read_lines(b"foo\nbar\nbaz\n", b"  foo\n\tbar\n    baz\n", &mk_args(0));
+        test_read_lines(b"- foo\n  bar\n", b"    - foo bar\n", &mk_args(5));
+    }
+
+    #[test]
+    fn test_number_lines() {
+        let mk_args = |number_width_: usize| Config{ max_line_length: 7, number_lines: true, number_width: number_width_, ..Config::default() };
+        test_read_lines(b"     1\tfoo bar\n     2\tbaz\n     3\t\n", b"foo bar baz\n\n", &mk_args(6));
+        let input = "foo\n".repeat(10);
+        let expected = (1..=10).map(|number| format!("{}\tfoo\n", number)).collect::<String>();
+        test_read_lines(expected.as_bytes(), input.as_bytes(), &mk_args(0));
+    }
+
+    #[test]
+    fn test_number_nonblank() {
+        let args = Config{ max_line_length: 7, number_nonblank: true, number_width: 2, ..Config::default() };
+        test_read_lines(b" 1\tfoo bar\n 2\tbaz\n\n\n 3\tfoo\n\n", b"foo bar baz\n\n \nfoo\n\n", &args);
+        assert!(Cli::try_parse_from(["formatter", "--number-lines", "--number-nonblank"]).is_err());
+        assert!(Cli::try_parse_from(["formatter", "--number-width", "2"]).is_err());
+        assert!(Cli::try_parse_from(["formatter", "--number-nonblank", "--number-width", "2"]).is_ok());
+    }
+
+    #[test]
+    fn test_comment_prefix() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 20, rewrap: rewrap_, comment_prefix: Some("//".to_owned()), ..Config::default() };
+        test_read_lines(b"// some long comment\n// which needs to be\n// wrapped\n", b"// some long comment which needs to be wrapped\n", &mk_args(false));
+        test_read_lines(b"    // indented\n    // comment\n", b"    // indented comment\n", &mk_args(false));
+        test_read_lines(b"// some long comment\n// which needs to be\n// rewrapped\n//\n// foo\nlet foo = bar;\n", b"// some long\n// comment which needs to be rewrapped\n//\n// foo\nlet foo = bar;\n", &mk_args(true));
+    }
+
+    #[test]
+    fn test_hanging_indent() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 12, rewrap: rewrap_, hanging_indent: 2, preserve_list_indentation: true, ..Config::default() };
+        test_read_lines(b"foo bar baz\n  foo bar\n  baz\n", b"foo bar baz foo bar baz\n", &mk_args(false));
+        test_read_lines(b"foo bar baz\n  foo bar\n  baz\n\nfoo bar baz\n  foo\n", b"foo bar\nbaz foo\nbar baz\n\nfoo bar baz foo\n", &mk_args(true));
+        test_read_lines(b"10. foo bar\n    baz\n", b"10. foo bar baz\n", &mk_args(false));
+    }
+
+    #[test]
+    fn test_transform_case() {
+        let mk_args = |case_: Case| Config{ max_line_length: 10, transform_case: Some(case_), ..Config::default() };
+        let input = "mIxEd cAsE ßtraße élan\n".as_bytes();
+        test_read_lines("MIXED CASE\nSSTRASSE\nÉLAN\n".as_bytes(), input, &mk_args(Case::Upper));
+        test_read_lines("mixed case\nßtraße\nélan\n".as_bytes(), input, &mk_args(Case::Lower));
+        test_read_lines("Mixed Case\nSstraße\nÉlan\n".as_bytes(), input, &mk_args(Case::Title));
+        test_read_lines(b"FOO\n", b"foo\n", &Config{ transform_case: Some(Case::Upper), ..Config::default() });
+    }
+
+    #[test]
+    fn test_max_words() {
+        let mk_args = |max_line_length_: usize, rewrap_: bool| Config{ max_line_length: max_line_length_, max_words: 3, rewrap: rewrap_, ..Config::default() };
+        let input = b"The quick brown fox jumps over the lazy dog\nfoo bar\nbaz\n";
+        test_read_lines(b"The quick brown\nfox jumps over\nthe lazy dog\nfoo bar\nbaz\n", input, &mk_args(0, false));
+        test_read_lines(b"The quick\nbrown fox\njumps over\nthe lazy\ndog\nfoo bar\nbaz\n", input, &mk_args(10, false));
+        test_read_lines(b"The quick brown\nfox jumps over\nthe lazy dog\nfoo bar baz\n", input, &mk_args(0, true));
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let mk_args = |prefix_: Option<&str>| Config{
+            max_line_length: 12, strip_prefix: Some(Regex::new(r"\[[A-Z]+\] ").unwrap()), prefix: prefix_.map(str::to_owned), ..Config::default() };
+        let input = b"[INFO] foo bar baz\nfoo [INFO] bar\n[WARN] foo\n";
+        test_read_lines(b"foo bar baz\nfoo [INFO]\nbar\nfoo\n", input, &mk_args(None));
+        test_read_lines(b"> foo bar\n> baz\n> foo [INFO]\n> bar\n> foo\n", input, &mk_args(Some("> ")));
+    }
+
+    #[test]
+    fn test_label_indent() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 20, rewrap: rewrap_, label_indent: Some(Regex::new(r"^\w+:\s+").unwrap()), ..Config::default() };
+        let input = b"Warning: this is a long text that wraps\nfoo bar baz foo bar baz\n";
+        test_read_lines(b"Warning: this is a\n         long text\n         that wraps\nfoo bar baz foo bar\nbaz\n", input, &mk_args(false));
+        test_read_lines(b"Warning: this is a\n         long text\n         that wraps\n         foo bar baz\n         foo bar baz\n", input, &mk_args(true));
+    }
+
+    #[test]
+    fn test_wrap_if() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 8, rewrap: rewrap_, wrap_if: Some(Regex::new("^[^|]").unwrap()), ..Config::default() };
+        let input = b"foo bar baz\n| foo | bar |\n|-----|-----|\nfoo\nbar\n";
+        test_read_lines(b"foo bar\nbaz\n| foo | bar |\n|-----|-----|\nfoo\nbar\n", input, &mk_args(false));
+        test_read_lines(b"foo bar\nbaz\n| foo | bar |\n|-----|-----|\nfoo bar\n", input, &mk_args(true));
+    }
+
+    #[test]
+    fn test_detect_tables() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 10, rewrap: rewrap_, collapse_whitespace: true, detect_tables: true, ..Config::default() };
+        let input = b"foo bar  baz qux\nName   Size  Type\nfoo.rs    42  file\n| a | b | c |\n+---+---+\nDone.  Next  one\n";
+        let table = "Name   Size  Type\nfoo.rs    42  file\n| a | b | c |\n+---+---+\n";
+        test_read_lines(format!("foo bar\nbaz qux\n{}Done. Next\none\n", table).as_bytes(), input, &mk_args(false));
+        test_read_lines(format!("foo bar\nbaz qux\n{}Done. Next\none\n", table).as_bytes(), input, &mk_args(true));
+        assert!(!is_table_row("| a | b | c |", &Config::default()));
+    }
+
+    #[test]
+    fn test_no_wrap_if() {
+        let mk_args = |rewrap_: bool| Config{
+            max_line_length: 10, rewrap: rewrap_, no_wrap_if: Some(Regex::new("https?://").unwrap()),
+            substitute_regex: vec!["http:".to_owned()], replacement: vec!["https:".to_owned()], ..Config::default() };
+        let input = b"see the docs at\nhttp://example.com/some/long/path for\nfurther details\n";
+        test_read_lines(b"see the\ndocs at\nhttps://example.com/some/long/path for\nfurther\ndetails\n", input, &mk_args(false));
+        test_read_lines(b"see the\ndocs at\nhttps://example.com/some/long/path for\nfurther\ndetails\n", input, &mk_args(true));
+    }
+
+    #[test]
+    fn test_verbatim_delimiter() {
+        let mk_args = |rewrap_: bool| Config{
+            max_line_length: 10, rewrap: rewrap_, verbatim_delimiter: Some("```".to_owned()), squeeze_blank: true,
+            substitute_regex: vec!["foo".to_owned()], replacement: vec!["FOO".to_owned()], ..Config::default() };
+        let input = b"foo bar baz\nqux\n```rust\nlet foo = \"bar baz qux\"; \n\n\n    foo();\n```\nfoo bar\n";
+        let verbatim = "```rust\nlet foo = \"bar baz qux\"; \n\n\n    foo();\n```\n";
+        test_read_lines(format!("FOO bar\nbaz\nqux\n{}FOO bar\n", verbatim).as_bytes(), input, &mk_args(false));
+        test_read_lines(format!("FOO bar\nbaz qux\n{}FOO bar\n", verbatim).as_bytes(), input, &mk_args(true));
+    }
+
+    #[test]
+    fn test_lines() {
+        let mk_args = |rewrap_: bool| Config{ max_line_length: 10, rewrap: rewrap_, lines: Some(LineRange { start: 2, end: 3 }), ..Config::default() };
+        let input = b"one two three four\nfoo bar baz\nqux\nfoo bar baz  \nfive\n";
+        test_read_lines(b"one two three four\nfoo bar\nbaz\nqux\nfoo bar baz  \nfive\n", input, &mk_args(false));
+        test_read_lines(b"one two three four\nfoo bar\nbaz qux\nfoo bar baz  \nfive\n", input, &mk_args(true));
+        // lines outside of the range are passed through without decorations
+        let config = Config{ prefix: Some("# ".to_owned()), ..mk_args(false) };
+        test_read_lines(b"one two three four\n# foo bar\n# baz\n# qux\nfoo bar baz  \nfive\n", input, &config);
+        let config = Config{ indent: 2, number_lines: true, number_width: 1, ..mk_args(false) };
+        test_read_lines(b"one two three four\n1\t  foo bar\n2\t  baz\n3\t  qux\nfoo bar baz  \nfive\n", input, &config);
+        assert_eq!(parse_line_range("2:3"), Ok(LineRange { start: 2, end: 3 }));
+        assert!(parse_line_range("0:3").is_err());
+        assert!(parse_line_range("3:2").is_err());
+        assert!(parse_line_range("3").is_err());
+    }
+
+    #[test]
+    fn test_byte_range() {
+        let mk_args = |start: usize, end: usize| Config{ max_line_length: 10, rewrap: true, byte_range: Some(start..end), ..Config::default() };
+        let input = b"one two three four\nfoo bar baz\nqux\nfoo bar baz  \nfive\n";
+        let expected = b"one two three four\nfoo bar\nbaz qux\nfoo bar baz  \nfive\n";
+        test_read_lines(expected, input, &mk_args(19, 35));
+        test_read_lines(expected, input, &mk_args(23, 32));
+        test_read_lines(b"one two\nthree four\nfoo bar baz\nqux\nfoo bar baz  \nfive\n", input, &mk_args(18, 19));
+        assert_eq!(parse_byte_range("0:42"), Ok(0..42));
+        assert!(parse_byte_range("42:42").is_err());
+    }
+
+    #[test]
+    fn test_indent() {
+        let mk_args = |indent_: usize, indent_string_: Option<&str>| Config{ max_line_length: 10, indent: indent_, indent_string: indent_string_.map(str::to_owned), ..Config::default() };
+        test_read_lines(b"    foo\n    bar\n\n      baz\n", b"foo bar\n\n  baz\n", &mk_args(4, None));
+        test_read_lines(b"> foo bar\n> baz\n", b"foo bar baz\n", &mk_args(0, Some("> ")));
+    }
+
+    #[test]
+    fn test_right_margin() {
+        let mk_args = |right_margin_: usize, suffix_: Option<&str>| Config{ max_line_length: 11, right_margin: right_margin_, suffix: suffix_.map(str::to_owned), ..Config::default() };
+        test_read_lines(b"foo bar baz\nqux\n", b"foo bar baz qux\n", &mk_args(0, None));
+        test_read_lines(b"foo bar\nbaz qux\n", b"foo bar baz qux\n", &mk_args(3, None));
+        test_read_lines(b"foo   |\nbar   |\n", b"foo bar\n", &mk_args(4, Some("|")));
+        assert!(matches!(format_string(&mk_args(11, None), "foo"), Err(FormatError::Config(ConfigError::RightMargin { right_margin: 11, max_line_length: 11 }))));
+        assert!(matches!(Formatter::new(mk_args(12, None)), Err(FormatError::Config(ConfigError::RightMargin { .. }))));
+    }
+
+    #[test]
+    fn test_prefix_suffix() {
+        let mk_args = |suffix_: Option<&str>| Config{ max_line_length: 13, prefix: Some("| ".to_owned()), suffix: suffix_.map(str::to_owned), ..Config::default() };
+        test_read_lines(b"| foo bar   |\n| baz       |\n|           |\n| foobar    |\n", b"foo bar baz\n\nfoobar  \n", &mk_args(Some(" |")));
+        test_read_lines(b"| foo bar baz\n| foobar\n", b"foo bar baz foobar\n", &mk_args(None));
+    }
+
+    #[test]
+    fn test_rewrap_respect_indent() {
+        let mk_args = |rewrap_respect_indent_: bool| Config{ max_line_length: 30, rewrap: true, rewrap_respect_indent: rewrap_respect_indent_, ..Config::default() };
+        let input = b"foo\nbar\n    let foo = 1;\n    let bar = 2;\n  baz\n- foo\n  bar\n- 1.\n     foo\n";
+        test_read_lines(b"foo bar\n    let foo = 1; let bar = 2;\n  baz\n- foo bar\n- 1.\n     foo\n", input, &mk_args(true));
+        test_read_lines(b"foo bar let foo = 1; let bar =\n2; baz\n- foo bar\n- 1. foo\n", input, &mk_args(false));
+    }
+
+    #[test]
+    fn test_rewrap_keep_sentences() {
+        let mk_args = |rewrap_keep_sentences_: bool| Config{ max_line_length: 30, rewrap: true, rewrap_keep_sentences: rewrap_keep_sentences_, ..Config::default() };
+        test_read_lines(b"Hello.\nWorld\n", b"Hello.\nWorld\n", &mk_args(true));
+        test_read_lines(b"Hello World\n", b"Hello\nWorld\n", &mk_args(true));
+        test_read_lines(b"Hello. World\n", b"Hello.\nWorld\n", &mk_args(false));
+        test_read_lines(b"\"Foo?\"\n(Bar!)\nbaz, foo bar\n", b"\"Foo?\"\n(Bar!)\nbaz, foo\nbar\n", &mk_args(true));
+    }
+
+    #[test]
+    fn test_preserve_indented_code() {
+        let mk_args = |rewrap_: bool, indented_code_width_: usize| Config{
+            max_line_length: 16, rewrap: rewrap_, preserve_indented_code: true, indented_code_width: indented_code_width_, ..Config::default() };
+        let input = b"Some prose\nfollows:\n    let foo = bar(baz);\n    qux();\nand more prose\n";
+        test_read_lines(b"Some prose\nfollows:\n    let foo =\n    bar(baz);\n    qux();\nand more prose\n", input, &mk_args(false, 4));
+        test_read_lines(b"Some prose\nfollows:\n    let foo =\n    bar(baz);\n    qux();\nand more prose\n", input, &mk_args(true, 4));
+        test_read_lines(b"Some prose\nfollows: let foo\n= bar(baz);\nqux(); and more\nprose\n", input, &mk_args(true, 5));
+    }
+
+    #[test]
+    fn test_rewrap_min_length() {
+        let mk_args = |max_line_length_: usize| Config{ max_line_length: max_line_length_, rewrap: true, rewrap_min_length: 10, ..Config::default() };
+        let input = b"foo\nbar\nthis line is long enough\nbaz\nshort\nfoo bar\n";
+        test_read_lines(b"foo bar this line is\nlong enough\nbaz short foo bar\n", input, &mk_args(20));
+        test_read_lines(b"foo bar this line is long enough\nbaz short foo bar\n", input, &mk_args(0));
+    }
+
+    #[test]
+    fn test_ansi() {
+        let mk_args = |ansi_: AnsiMode| Config{ max_line_length: 7, ansi: ansi_, ..Config::default() };
+        let input = b"\x1b[31mfoo\x1b[0m \x1b[1;32mbar\x1b[0m baz\n";
+        test_read_lines(b"\x1b[31mfoo\x1b[0m\n\x1b[1;32mbar\x1b[0m\nbaz\n", input, &mk_args(AnsiMode::Keep));
+        test_read_lines(b"\x1b[31mfoo\x1b[0m \x1b[1;32mbar\x1b[0m\nbaz\n", input, &mk_args(AnsiMode::Ignore));
+        test_read_lines(b"foo bar\nbaz\n", input, &mk_args(AnsiMode::Strip));
+    }
+
+    #[test]
+    fn test_substitution() {
+        let mk_args = |_substitute_regex: Vec<String>, _replacement: Vec<String>|
+        Config{ max_line_length: 20, preserve_list_indentation: true, substitute_regex: _substitute_regex, replacement: _replacement, ..Config::default() };
+        test_read_lines(b"f00bar\nf00baz\n", b"foobar\nfoobaz\n", &mk_args(vec!["oo".to_owned(), "remove".to_owned()], vec!["00".to_owned()]));
+        test_read_lines(b"b-baz fo\n", b"foo-bar-baz\n", &mk_args(vec!["(b)ar-".to_owned(), "^(foo)-(.*)$".to_owned(), "o".to_owned()], vec!["$1-".to_owned(), "$2 $1".to_owned()]));
+    }
+
+    #[test]
+    fn test_substitution_flags() {
+        let mk_args = |substitute_flags_: Vec<String>|
+            Config{ substitute_regex: vec!["foo".to_owned(), "bar".to_owned()], replacement: vec!["x".to_owned(), "y".to_owned()], substitute_flags: substitute_flags_, ..Config::default() };
+        test_read_lines(b"FOO BAR\n", b"FOO BAR\n", &mk_args(Vec::new()));
+        test_read_lines(b"x BAR\n", b"FOO BAR\n", &mk_args(vec!["i".to_owned()]));
+        test_read_lines(b"FOO y\n", b"FOO BAR\n", &mk_args(vec!["".to_owned(), "is".to_owned()]));
+        assert!(matches!(format_string(&mk_args(vec!["ix".to_owned()]), "foo"), Err(FormatError::Config(ConfigError::RegexFlags { flag: 'x', .. }))));
+    }
+
+    #[test]
+    fn test_replacement_count() {
+        let mk_args = |strict_: bool|
+            Config{ substitute_regex: vec!["foo".to_owned()], replacement: vec!["x".to_owned(), "y".to_owned()], strict: strict_, ..Config::default() };
+        test_read_lines(b"x bar\n", b"foo bar\n", &mk_args(false));
+        let result = format_string(&mk_args(true), "foo bar\n");
+        assert!(matches!(result, Err(FormatError::Config(ConfigError::ReplacementCount { regexes: 1, replacements: 2 }))));
+        assert_eq!("--replacement given 2 times but --substitute-regex only 1 time; the extra replacements are not used", result.unwrap_err().to_string());
+        let error = ConfigError::ReplacementCount { regexes: 0, replacements: 1 };
+        assert_eq!("--replacement given 1 time but --substitute-regex only 0 times; the extra replacements are not used", error.to_string());
+    }
+
+    #[test]
+    fn test_skip_invalid_rules() {
+        let mk_args = |skip_invalid_rules_: bool| Config{
+            substitute_regex: vec!["(".to_owned(), "o+".to_owned()], replacement: vec!["x".to_owned(), "0".to_owned()],
+            skip_invalid_rules: skip_invalid_rules_, quiet: true, ..Config::default() };
+        test_read_lines(b"f0 bar\n", b"foo bar\n", &mk_args(true));
+        assert!(matches!(format_string(&mk_args(false), "foo bar\n"), Err(FormatError::Config(ConfigError::Regex { regex, .. })) if regex == "("));
+    }
+
+    #[test]
+    fn test_capture_group_references() {
+        let mk_args = |replacement_: &str, strict_: bool|
+            Config{ substitute_regex: vec!["(?<first>f)(o+)".to_owned()], replacement: vec![replacement_.to_owned()], strict: strict_, ..Config::default() };
+        test_read_lines(b"oof bar\n", b"foo bar\n", &mk_args("$2${first}", true));
+        test_read_lines(b"$1oo bar\n", b"foo bar\n", &mk_args("$$1$2", true));
+        test_read_lines(b" bar\n", b"foo bar\n", &mk_args("$3", false));
+        for (replacement, reference) in [("$3", "3"), ("${2}$second", "second"), ("${firsts}", "firsts"), ("$firsts", "firsts")] {
+            let result = format_string(&mk_args(replacement, true), "foo bar\n");
+            assert!(matches!(result, Err(FormatError::Config(ConfigError::ReplacementReference { reference: r, .. })) if r == reference), "{}", replacement);
+        }
+    }
+
+    #[test]
+    fn test_config_file() {
+        let path = std::env::temp_dir().join(format!("formatter-test-config-{}.toml", std::process::id()));
+        fs::write(&path, "max_line_length = 20\nbreak_words = true\nwidth_mode = \"display\"\nlist_markers = \"+\"\nsubstitute_regex = [\"foo\", \"bar\"]\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let cli = parse_cli(["formatter", "--config", path]);
+        assert_eq!(20, cli.config.max_line_length);
+        assert!(cli.config.break_words);
+        assert_eq!(WidthMode::Display, cli.config.width_mode);
+        assert_eq!("+", cli.config.list_markers);
+        assert_eq!(vec!["foo".to_owned(), "bar".to_owned()], cli.config.substitute_regex);
+
+        // options specified on the command-line take precedence
+        let cli = parse_cli(["formatter", "-m", "10", "--substitute-regex", "baz", &format!("--config={}", path)]);
+        assert_eq!(10, cli.config.max_line_length);
+        assert!(cli.config.break_words);
+        assert_eq!(vec!["baz".to_owned()], cli.config.substitute_regex);
+
+        fs::write(path, "max_line_lenght = 20\n").unwrap();
+        assert!(apply_config_file(Cli::command(), Some(path), &mut Vec::new()).unwrap_err().contains("unknown option \"max_line_lenght\""));
+        fs::remove_file(path).unwrap();
+        assert!(apply_config_file(Cli::command(), Some(path), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_env_vars() {
+        let mk_cli = |args: &[&str], vars: &[(&str, &str)]| {
+            let vars: Vec<(String, String)> = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+            parse_cli_with_env(args.iter().copied(), move |name| vars.iter().find(|(var, _)| var == name).map(|(_, value)| value.clone()))
+        };
+        let args = ["formatter", "--substitute-regex", "foo", "--replacement", "$1"];
+        assert!(format_string(&mk_cli(&args, &[]).config, "foo").is_ok());
+        let cli = mk_cli(&args, &[("FORMATTER_STRICT", "true")]);
+        assert!(matches!(format_string(&cli.config, "foo"), Err(FormatError::Config(ConfigError::ReplacementReference { .. }))));
+        assert!(!mk_cli(&args, &[("FORMATTER_STRICT", "0")]).config.strict);
+
+        // options on the command-line take precedence over environment variables and those over the configuration file
+        let path = std::env::temp_dir().join(format!("formatter-test-env-config-{}.toml", std::process::id()));
+        fs::write(&path, "max_line_length = 20\nindent = 2\n").unwrap();
+        let vars = [("FORMATTER_CONFIG_FILE", path.to_str().unwrap()), ("FORMATTER_MAX_LINE_LENGTH", "30"), ("FORMATTER_BREAK_WORDS", "true")];
+        let cli = mk_cli(&["formatter"], &vars);
+        assert_eq!((30, 2, true), (cli.config.max_line_length, cli.config.indent, cli.config.break_words));
+        assert_eq!(40, mk_cli(&["formatter", "-m", "40"], &vars).config.max_line_length);
+
+        // a max line length from the environment or the configuration file is not overridden by --auto-width
+        assert_eq!(3, mk_cli(&["formatter", "--auto-width"], &[("FORMATTER_MAX_LINE_LENGTH", "3")]).config.max_line_length);
+        assert_eq!(20, mk_cli(&["formatter", "--auto-width"], &vars[..1]).config.max_line_length);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_auto_width() {
+        let resolve_with = |args: &[&str], config_file: Option<&str>, vars: &[(&str, &str)], terminal_width: Option<usize>| {
+            let env = |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value.to_string());
+            let mut configured_options = Vec::new();
+            let command = apply_config_file(Cli::command(), config_file, &mut configured_options).unwrap();
+            let matches = apply_env_vars(command, &env, &mut configured_options).get_matches_from(args);
+            let mut cli = Cli::from_arg_matches(&matches).unwrap();
+            resolve_auto_width(&mut cli, &matches, &configured_options, terminal_width);
+            cli.config.max_line_length
+        };
+        let resolve = |args: &[&str], terminal_width: Option<usize>| resolve_with(args, None, &[], terminal_width);
+        assert_eq!(120, resolve(&["formatter", "--auto-width"], Some(120)));
+        assert_eq!(80, resolve(&["formatter", "--auto-width"], None));
+        assert_eq!(40, resolve(&["formatter", "--auto-width", "-m", "40"], Some(120)));
+        assert_eq!(0, resolve(&["formatter"], Some(120)));
+
+        // a max line length from the environment or the configuration file counts as explicit as well
+        let env_vars = [("FORMATTER_MAX_LINE_LENGTH", "30")];
+        assert_eq!(30, resolve_with(&["formatter", "--auto-width"], None, &env_vars, Some(120)));
+        assert_eq!(40, resolve_with(&["formatter", "--auto-width", "-m", "40"], None, &env_vars, Some(120)));
+        assert_eq!(120, resolve_with(&["formatter", "--auto-width"], None, &[("FORMATTER_INDENT", "2")], Some(120)));
+        let path = std::env::temp_dir().join(format!("formatter-test-auto-width-{}.toml", std::process::id()));
+        fs::write(&path, "max_line_length = 20\n").unwrap();
+        assert_eq!(20, resolve_with(&["formatter", "--auto-width"], path.to_str(), &[], Some(120)));
+        assert_eq!(30, resolve_with(&["formatter", "--auto-width"], path.to_str(), &env_vars, None));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_literal_substitution() {
+        let mk_args = |substitute_regex_: Vec<String>, substitute_flags_: Vec<String>|
+            Config{ substitute_regex: substitute_regex_, replacement: vec!["!".to_owned(), "$0?".to_owned()], substitute_flags: substitute_flags_, ..Config::default() };
+        test_read_lines(b"foo!\n", b"foo.\n", &mk_args(vec![".".to_owned()], vec!["l".to_owned()]));
+        test_read_lines(b"!oo.\n", b"foo.\n", &mk_args(vec![".".to_owned()], Vec::new()));
+        test_read_lines(b"foo!$0?\n", b"foo..\n", &mk_args(vec![".".to_owned(), ".".to_owned()], vec!["l".to_owned(), "l".to_owned()]));
+
+        let cli = parse_cli(["formatter", "--substitute-literal", ".", "--replacement", "!", "-s", "o+", "--replacement", "0", "--substitute-literal", "0!"]);
+        assert_eq!(vec![".".to_owned(), "o+".to_owned(), "0!".to_owned()], cli.config.substitute_regex);
+        assert_eq!(vec!["l".to_owned(), String::new(), "l".to_owned()], cli.config.substitute_flags);
+        let mut output = Vec::new();
+        assert_eq!(0, run_cli(&cli, &mut output, &mut Cursor::new("foo.\n")));
+        assert_eq!("f\n", String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn test_substitution_count() {
+        let mk_args = |substitute_count_: Vec<usize>, substitute_multiline_: bool|
+            Config{ substitute_regex: vec!["o".to_owned(), "a".to_owned()], replacement: vec!["0".to_owned(), "4".to_owned()], substitute_flags: vec![String::new(), "l".to_owned()], substitute_count: substitute_count_, substitute_multiline: substitute_multiline_, ..Config::default() };
+        test_read_lines(b"f0o b4aaa\nf0o\n", b"foo baaaa\nfoo\n", &mk_args(Vec::new(), false));
+        test_read_lines(b"f00 b444a\nf00\n", b"foo baaaa\nfoo\n", &mk_args(vec![0, 3], false));
+        test_read_lines(b"f00 b4444\nf00\n", b"foo baaaa\nfoo\n", &mk_args(Vec::new(), true));
+        test_read_lines(b"f00 b4aaa\nf0o\n", b"foo baaaa\nfoo\n", &mk_args(vec![3, 1], true));
+    }
+
+    #[test]
+    fn test_drop_emptied_lines() {
+        let mk_args = |drop_emptied_lines_: bool, rewrap_: bool| Config{
+            max_line_length: 20, rewrap: rewrap_, substitute_regex: vec!["^DEBUG.*".to_owned()], drop_emptied_lines: drop_emptied_lines_, ..Config::default() };
+        let input = b"foo\nDEBUG bar\n\nbaz\nDEBUG\nqux\n";
+        test_read_lines(b"foo\n\n\nbaz\n\nqux\n", input, &mk_args(false, false));
+        test_read_lines(b"foo\n\nbaz\nqux\n", input, &mk_args(true, false));
+        test_read_lines(b"foo\n\nbaz qux\n", input, &mk_args(true, true));
+    }
+
+    #[test]
+    fn test_substitution_log() {
+        let log_file_path = std::env::temp_dir().join(format!("formatter-test-substitution-log-{}", std::process::id()));
+        let log_file_path = log_file_path.to_str().unwrap();
+        let config = Config{
+            substitute_regex: vec!["o+".to_owned(), "a".to_owned(), "z".to_owned()], replacement: vec!["0".to_owned(), "a".to_owned(), "Z".to_owned()],
+            substitution_log: Some(log_file_path.to_owned()), ..Config::default() };
+        let read_log = || -> Vec<serde_json::Value> {
+            fs::read_to_string(log_file_path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+        };
+        test_read_lines(b"f0 bar\nbaZ\nqux\nf0Z\n", b"foo bar\nbaz\nqux\nfoz\n", &config);
+        assert_eq!(vec![
+            serde_json::json!({"path": "<stdin>", "line": 1, "rule": 0, "before": "foo bar", "after": "f0 bar"}),
+            serde_json::json!({"path": "<stdin>", "line": 2, "rule": 2, "before": "baz", "after": "baZ"}),
+            serde_json::json!({"path": "<stdin>", "line": 4, "rule": 0, "before": "foz", "after": "f0z"}),
+            serde_json::json!({"path": "<stdin>", "line": 4, "rule": 2, "before": "f0z", "after": "f0Z"}),
+        ], read_log());
+
+        // the records written before an error are kept
+        let result = format(&config, &mut Cursor::new(b"foo\n\xff\n"), &mut Vec::new());
+        assert!(matches!(result, Err(FormatError::Io(_))));
+        assert_eq!(vec![serde_json::json!({"path": "<stdin>", "line": 1, "rule": 0, "before": "foo", "after": "f0"})], read_log());
+
+        // line numbers are relative to the input file
+        let config = Config{ input_files: vec!["-".to_owned(), "testfiles/testinput1".to_owned()], ..config };
+        test_read_lines(b"baZ\nf0\nbar\n", b"baz\n", &config);
+        assert_eq!(vec![
+            serde_json::json!({"path": "<stdin>", "line": 1, "rule": 2, "before": "baz", "after": "baZ"}),
+            serde_json::json!({"path": "testfiles/testinput1", "line": 1, "rule": 0, "before": "foo", "after": "f0"}),
+        ], read_log());
+        fs::remove_file(log_file_path).unwrap();
+
+        let config = Config{ substitution_log: Some("testfiles/missing/log".to_owned()), ..Config::default() };
+        assert!(matches!(format_string(&config, "foo\n"), Err(FormatError::SubstitutionLog { .. })));
+    }
+
+    #[test]
+    fn test_substitution_file() {
+        let rule_file_path = std::env::temp_dir().join(format!("formatter-test-substitution-file-{}", std::process::id()));
+        let rule_file_path = rule_file_path.to_str().unwrap();
+        let mk_args = || Config{ substitute_regex: vec!["o".to_owned()], replacement: vec!["0".to_owned()], substitute_file: Some(rule_file_path.to_owned()), ..Config::default() };
+        fs::write(rule_file_path, "# comment\n\n(b)ar\t$1-\n0\tO\n").unwrap();
+        test_read_lines(b"fOo b-\n", b"foo bar\n", &mk_args());
+
+        fs::write(rule_file_path, "bar\tbaz\n\nfoo bar\n").unwrap();
+        let error = format_string(&mk_args(), "foo").unwrap_err();
+        assert!(matches!(error, FormatError::Config(ConfigError::SubstitutionFileLine { line_number: 3, .. })));
+        fs::write(rule_file_path, "(\tbaz\n").unwrap();
+        assert!(matches!(format_string(&mk_args(), "foo"), Err(FormatError::Config(ConfigError::SubstitutionFileLine { line_number: 1, .. }))));
+        fs::write(rule_file_path, "(\tbaz\nf\tF\n").unwrap();
+        test_read_lines(b"F0o\n", b"foo\n", &Config{ skip_invalid_rules: true, quiet: true, ..mk_args() });
+        let warnings = Config{ skip_invalid_rules: true, ..mk_args() }.validate().unwrap();
+        assert!(matches!(&warnings[..], [ConfigError::SkippedRule { error }] if matches!(**error, ConfigError::SubstitutionFileLine { line_number: 1, .. })));
+        fs::remove_file(rule_file_path).unwrap();
+
+        assert!(matches!(format_string(&mk_args(), "foo"), Err(FormatError::Config(ConfigError::SubstitutionFile { .. }))));
+    }
+
+    #[test]
+    fn test_multiline_substitution() {
+        let mk_args = |substitute_multiline_: bool, max_line_length_: usize|
+            Config{ max_line_length: max_line_length_, substitute_regex: vec!["(?s)<!--.*?-->\n?".to_owned(), "o".to_owned()], replacement: vec![String::new(), "0".to_owned()], substitute_multiline: substitute_multiline_, ..Config::default() };
+        test_read_lines(b"f00\nbar baz\nf00 bar\n", b"foo\n<!-- a\nmultiline comment -->\nbar baz\n<!-- another one -->\nfoo bar\n", &mk_args(true, 0));
+        test_read_lines(b"f00\nbar\nbaz\nf00\nbar\n", b"foo\n<!-- a\nmultiline comment -->\nbar baz\n<!-- another one -->\nfoo bar\n", &mk_args(true, 4));
+        test_read_lines(b"f0o\n<!-- a\nmultiline c0mment -->\nbar baz\n\nf0o bar\n", b"foo\n<!-- a\nmultiline comment -->\nbar baz\n<!-- another one -->\nfoo bar\n", &mk_args(false, 0));
+    }
+
+    #[test]
+    fn test_lossy_decoding() {
+        let mk_args = |encoding_: Encoding| Config{ encoding: encoding_, ..Config::default() };
+        test_read_lines("foo\nb\u{fffd}r\nbaz\n".as_bytes(), b"foo\nb\xffr\nbaz\n", &mk_args(Encoding::Lossy));
+        test_read_lines(b"foo\nbar", b"foo\r\nbar", &mk_args(Encoding::Strict));
+        assert!(matches!(format(&mk_args(Encoding::Strict), &mut Cursor::new(b"b\xffr\n"), &mut Vec::new()), Err(FormatError::Io(_))));
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let mk_args = |line_ending_: LineEnding| Config{ max_line_length: 3, line_ending: line_ending_, ..Config::default() };
+        test_read_lines(b"foo\nbar\nbaz\n", b"foo bar\r\nbaz\n", &mk_args(LineEnding::Lf));
+        test_read_lines(b"foo\r\nbar\r\nbaz\r\n", b"foo bar\r\nbaz\n", &mk_args(LineEnding::Crlf));
+        test_read_lines(b"foo\r\nbar\r\nbaz\n", b"foo bar\r\nbaz\n", &mk_args(LineEnding::Preserve));
+        test_read_lines(b"foo\nbar\r\n", b"foo\nbar\r\n", &mk_args(LineEnding::Preserve));
+    }
+
+    #[test]
+    fn test_missing_final_line_ending() {
+        let mk_args = |max_line_length_: usize, rewrap_: bool| Config{ max_line_length: max_line_length_, rewrap: rewrap_, ..Config::default() };
+        test_read_lines(b"foo\nbar", b"foo bar", &mk_args(3, false));
+        test_read_lines(b"foo\nbar", b"foo bar", &mk_args(3, true));
+        test_read_lines(b"foo bar", b"foo\nbar", &mk_args(0, true));
+        test_read_lines(b"foo bar\n", b"foo\nbar\n", &mk_args(0, true));
+    }
+
+    #[test]
+    fn test_spaces_to_tabs() {
+        let mk_args = |tab_width_: usize| Config{ spaces_to_tabs: 4, tab_width: tab_width_, ..Config::default() };
+        test_read_lines(b"\t\tfoo    bar\n\t  baz\n\t\t qux\nfoo\n", b"        foo    bar\n      baz\n    \t qux\nfoo\n", &mk_args(0));
+        test_read_lines(b"\t  foo\n\t\t qux\n", b"  \t  foo\n    \t qux\n", &mk_args(4));
+    }
+
+    #[test]
+    fn test_tab_expansion() {
+        let mk_args = |max_line_length_: usize, tab_width_: usize| Config{ max_line_length: max_line_length_, tab_width: tab_width_, ..Config::default() };
+        test_read_lines(b"\tfoo\tbar\n", b"\tfoo\tbar\n", &mk_args(0, 0));
+        test_read_lines(b"    foo bar\n", b"\tfoo\tbar\n", &mk_args(0, 4));
+        test_read_lines(b"ab  cdef    g\n", b"ab\tcdef\tg\n", &mk_args(0, 4));
+        test_read_lines(b"        foo\nbar\n", b"\tfoo bar\n", &mk_args(12, 8));
+        test_read_lines(b"\tfoo\tbar\n", b"\tfoo\tbar\n", &mk_args(12, 0));
+    }
+
+    #[test]
+    fn test_format_string() {
+        let config = Config{ max_line_length: 7, ..Config::default() };
+        assert_eq!("foo bar\nbaz\n", format_string(&config, "foo bar baz\n").unwrap());
+        assert_eq!("foo bar\nbaz", format_string(&config, "foo bar baz").unwrap());
+        assert_eq!("", format_string(&config, "").unwrap());
+    }
+
+    #[test]
+    fn test_wrap_line() {
+        let config = Config{ max_line_length: 5, preserve_list_indentation: true, ..Config::default() };
+        assert_eq!(vec!["- foo".to_owned(), "  bar".to_owned()], wrap_line("- foo bar", &config).unwrap());
+        assert_eq!(vec!["foo".to_owned(), "bar".to_owned()], wrap_line("foo bar", &Config{ rewrap: true, ..config.clone() }).unwrap());
+        assert_eq!(vec!["foo".to_owned()], wrap_line("foo", &Config{ substitute_regex: vec!["(".to_owned()], ..config.clone() }).unwrap());
+        assert_eq!(vec![String::new()], wrap_line("", &config).unwrap());
+
+        // options selecting or filtering lines are ignored
+        let filtering = Config{ lines: Some(LineRange { start: 2, end: 3 }), wrap_if: Some(Regex::new("x").unwrap()), truncate: true, remove_blank_lines: true, ensure_final_newline: true, ..config.clone() };
+        assert_eq!(vec!["foo".to_owned(), "bar".to_owned()], wrap_line("foo bar", &filtering).unwrap());
+        assert_eq!(vec![String::new()], wrap_line("", &filtering).unwrap());
+
+        assert!(matches!(wrap_line("foo", &Config{ right_margin: 5, ..config.clone() }), Err(FormatError::Config(ConfigError::RightMargin { .. }))));
+        assert!(matches!(wrap_line("foo\nbar", &config), Err(FormatError::Io(error)) if error.kind() == io::ErrorKind::InvalidInput));
+        assert_eq!(vec!["a\nb".to_owned()], wrap_line("a\nb", &Config{ null: true, ..config.clone() }).unwrap());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().unwrap().is_empty());
+        assert!(matches!(Config{ max_line_length: 10, right_margin: 10, ..Config::default() }.validate(), Err(ConfigError::RightMargin { .. })));
+        let config = Config{ output_dir: Some("out".to_owned()), input_files: vec!["a/foo".to_owned(), "b/foo".to_owned()], ..Config::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::OutputFileCollision { .. })));
+        assert!(matches!(Config{ substitute_regex: vec!["(".to_owned()], ..Config::default() }.validate(), Err(ConfigError::Regex { .. })));
+        let config = Config{ substitute_regex: vec!["a".to_owned()], substitute_flags: vec!["x".to_owned()], ..Config::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::RegexFlags { .. })));
+        let config = Config{ substitute_regex: vec!["a".to_owned()], replacement: vec!["b".to_owned(), "c".to_owned()], strict: true, ..Config::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::ReplacementCount { .. })));
+        let config = Config{ substitute_regex: vec!["a".to_owned()], replacement: vec!["$1".to_owned()], strict: true, ..Config::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::ReplacementReference { .. })));
+        let config = Config{ substitute_file: Some("testfiles/missing".to_owned()), ..Config::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::SubstitutionFile { .. })));
+        let config = Config{ substitute_file: Some("testfiles/testinput1".to_owned()), ..Config::default() };
+        assert!(matches!(config.validate(), Err(ConfigError::SubstitutionFileLine { line_number: 1, .. })));
+
+        // conflicts otherwise only rejected by clap
+        let conflicts = [
+            (Config{ substitution_log: Some("log".to_owned()), substitute_multiline: true, ..Config::default() }, "substitution-log", "substitute-multiline"),
+            (Config{ quiet: true, verbose: true, ..Config::default() }, "quiet", "verbose"),
+            (Config{ quiet: true, warn_long_words: true, ..Config::default() }, "quiet", "warn-long-words"),
+            (Config{ number_lines: true, number_nonblank: true, ..Config::default() }, "number-lines", "number-nonblank"),
+            (Config{ in_place: true, check: true, ..Config::default() }, "check", "in-place"),
+        ];
+        for (config, expected_option, expected_other_option) in conflicts {
+            let error = config.validate().unwrap_err();
+            assert!(matches!(error, ConfigError::ConflictingOptions { option, other_option } if option == expected_option && other_option == expected_other_option));
+        }
+
+        // problems that do not prevent formatting are returned as warnings instead of being printed
+        let warnings = Config{ align: Alignment::Center, ..Config::default() }.validate().unwrap();
+        assert!(matches!(warnings[..], [ConfigError::AlignWithoutMaxLineLength]));
+        let config = Config{ substitute_regex: vec!["a".to_owned()], replacement: vec!["b".to_owned(), "c".to_owned()], ..Config::default() };
+        assert!(matches!(config.validate().unwrap()[..], [ConfigError::ReplacementCount { regexes: 1, replacements: 2 }]));
+        let config = Config{ substitute_regex: vec!["(".to_owned(), "a".to_owned()], skip_invalid_rules: true, ..Config::default() };
+        let warnings = config.validate().unwrap();
+        assert!(matches!(&warnings[..], [ConfigError::SkippedRule { error }] if matches!(**error, ConfigError::Regex { .. })));
+        assert!(warnings[0].to_string().starts_with("Skipping invalid substitution rule: Unable to parse specified regex \"(\""));
+    }
+
+    #[test]
+    fn test_errors() {
+        let config = Config{ substitute_regex: vec!["(".to_owned()], ..Config::default() };
+        assert!(matches!(format_string(&config, "foo\n"), Err(FormatError::Config(ConfigError::Regex { regex, .. })) if regex == "("));
+
+        let config = Config{ input_files: vec!["testfiles/missing".to_owned(), "testfiles/testinput1".to_owned()], ..Config::default() };
+        let mut output = Vec::new();
+        let result = format(&config, &mut Cursor::new(""), &mut output);
+        assert!(matches!(result, Err(FormatError::FileOpen { path, .. }) if path == "testfiles/missing"));
+        assert_eq!("foo\nbar\n", String::from_utf8(output).unwrap());
+
+        let result = format(&Config::default(), &mut Cursor::new(b"foo\n\xff\n"), &mut Vec::new());
+        assert!(matches!(result, Err(FormatError::Io(_))));
+    }
+
+    #[test]
+    fn test_quiet_and_verbose() {
+        let mut output = Vec::new();
+        let cli = parse_cli(["formatter", "--quiet", "testfiles/missing", "testfiles/testinput1"]);
+        assert_eq!(1, run_cli(&cli, &mut output, &mut Cursor::new("")));
+        assert_eq!("foo\nbar\n", String::from_utf8(output).unwrap());
+
+        let cli = parse_cli(["formatter", "-q", "--substitute-regex", "("]);
+        assert_eq!(1, run_cli(&cli, &mut Vec::new(), &mut Cursor::new("foo\n")));
+
+        let mut output = Vec::new();
+        let cli = parse_cli(["formatter", "--verbose", "testfiles/testinput1", "-"]);
+        assert_eq!(0, run_cli(&cli, &mut output, &mut Cursor::new("baz\n")));
+        assert_eq!("foo\nbar\nbaz\n", String::from_utf8(output).unwrap());
+
+        assert!(Cli::try_parse_from(["formatter", "--quiet", "--verbose"]).is_err());
+        assert!(Cli::try_parse_from(["formatter", "--quiet", "--warn-long-words"]).is_err());
     }
 }
@@ -1,6 +1,13 @@
-use std::io;
+use std::io::{self, IsTerminal};
 use formatter::run;
 
 fn main() {
-    std::process::exit(run(&mut io::stdout().lock(), &mut io::stdin().lock()));
+    // buffer the output unless it is shown interactively as stdout itself is only line-buffered
+    let stdout = io::stdout();
+    let exit_code = if stdout.is_terminal() {
+        run(&mut stdout.lock(), &mut io::stdin().lock())
+    } else {
+        run(&mut io::BufWriter::new(stdout.lock()), &mut io::stdin().lock())
+    };
+    std::process::exit(exit_code);
 }
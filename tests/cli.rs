@@ -0,0 +1,28 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use formatter::{format_string, Config};
+
+#[test]
+fn test_binary_matches_library() {
+    let input = "A list follows:\n- foo bar baz\n- some longer item which needs wrapping\n\nfoo\nbar\n";
+    let mut child = Command::new(env!("CARGO_BIN_EXE_formatter"))
+        .args(["--max-line-length", "20", "--rewrap", "--preserve-list-indentation", "-s", "foo", "--replacement", "f00"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let config = Config {
+        max_line_length: 20,
+        rewrap: true,
+        preserve_list_indentation: true,
+        substitute_regex: vec!["foo".to_owned()],
+        replacement: vec!["f00".to_owned()],
+        ..Config::default()
+    };
+    assert_eq!(format_string(&config, input).unwrap(), String::from_utf8(output.stdout).unwrap());
+}